@@ -0,0 +1,167 @@
+use actix_web::HttpRequest;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Header carrying a user's API key.
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// No keys are configured, so nobody can be let in.
+    NotConfigured,
+    /// The key is missing, unknown or belongs to someone else.
+    InvalidKey,
+}
+
+/// Per-user API keys, read from `CEX_API_KEYS` as `user:key,user:key`. They
+/// guard listen keys and withdrawals. The admin endpoints take the separate
+/// `CEX_ADMIN_KEY` instead. Without the variables those requests are refused.
+#[derive(Debug, Default, Clone)]
+pub struct ApiKeys {
+    keys: Option<HashMap<String, String>>,
+    admin_key: Option<String>,
+}
+
+impl ApiKeys {
+    pub fn from_env() -> Result<Self, String> {
+        let admin_key = std::env::var("CEX_ADMIN_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty());
+        let Ok(spec) = std::env::var("CEX_API_KEYS") else {
+            return Ok(Self {
+                keys: None,
+                admin_key,
+            });
+        };
+        let keys = spec
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (user_id, key) = pair
+                    .split_once(':')
+                    .filter(|(user_id, key)| !user_id.is_empty() && !key.is_empty())
+                    .ok_or_else(|| format!("CEX_API_KEYS entry {:?} is not user:key", pair))?;
+                Ok((user_id.trim().to_string(), key.trim().to_string()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            keys: Some(keys),
+            admin_key,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_key.is_some()
+    }
+
+    /// Checks that `req` proves it acts for `user_id`.
+    pub fn verify(&self, req: &HttpRequest, user_id: &str) -> Result<(), AuthError> {
+        let keys = self.keys.as_ref().ok_or(AuthError::NotConfigured)?;
+        check_key(req, keys.get(user_id))
+    }
+
+    /// Checks that `req` carries the admin key.
+    pub fn verify_admin(&self, req: &HttpRequest) -> Result<(), AuthError> {
+        let admin_key = self.admin_key.as_ref().ok_or(AuthError::NotConfigured)?;
+        check_key(req, Some(admin_key))
+    }
+}
+
+fn check_key(req: &HttpRequest, expected: Option<&String>) -> Result<(), AuthError> {
+    let (Some(expected), Some(given)) = (
+        expected,
+        req.headers()
+            .get(API_KEY_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    ) else {
+        return Err(AuthError::InvalidKey);
+    };
+    // Compare digests so the time taken says nothing about the key
+    if Sha256::digest(expected.as_bytes()) == Sha256::digest(given.as_bytes()) {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn keys() -> ApiKeys {
+        ApiKeys {
+            keys: Some(HashMap::from([
+                ("alice".to_string(), "alice-key".to_string()),
+                ("bob".to_string(), "bob-key".to_string()),
+            ])),
+            admin_key: Some("admin-key".to_string()),
+        }
+    }
+
+    fn request(key: Option<&str>) -> HttpRequest {
+        let mut req = TestRequest::default();
+        if let Some(key) = key {
+            req = req.insert_header((API_KEY_HEADER, key));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn only_the_users_own_key_is_accepted() {
+        let keys = keys();
+        assert_eq!(keys.verify(&request(Some("alice-key")), "alice"), Ok(()));
+        assert_eq!(
+            keys.verify(&request(Some("wrong")), "alice"),
+            Err(AuthError::InvalidKey)
+        );
+        assert_eq!(
+            keys.verify(&request(None), "alice"),
+            Err(AuthError::InvalidKey)
+        );
+        assert_eq!(
+            keys.verify(&request(Some("bob-key")), "alice"),
+            Err(AuthError::InvalidKey)
+        );
+        assert_eq!(
+            keys.verify(&request(Some("alice-key")), "carol"),
+            Err(AuthError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn nothing_is_accepted_without_configured_keys() {
+        assert_eq!(
+            ApiKeys::default().verify(&request(Some("alice-key")), "alice"),
+            Err(AuthError::NotConfigured)
+        );
+        assert_eq!(
+            ApiKeys::default().verify_admin(&request(Some("admin-key"))),
+            Err(AuthError::NotConfigured)
+        );
+    }
+
+    #[test]
+    fn only_the_admin_key_opens_the_admin_endpoints() {
+        let keys = keys();
+        assert_eq!(keys.verify_admin(&request(Some("admin-key"))), Ok(()));
+        assert_eq!(
+            keys.verify_admin(&request(Some("alice-key"))),
+            Err(AuthError::InvalidKey)
+        );
+        assert_eq!(
+            keys.verify_admin(&request(None)),
+            Err(AuthError::InvalidKey)
+        );
+        // Nor does the admin key act for users
+        assert_eq!(
+            keys.verify(&request(Some("admin-key")), "alice"),
+            Err(AuthError::InvalidKey)
+        );
+    }
+}
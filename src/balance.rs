@@ -1,9 +1,26 @@
+use crate::ledger::{Account, FEE_ACCOUNT, Ledger, LedgerReason, Posting, TransferCause};
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+/// Funds held in a single token. `locked` is reserved by resting orders and
+/// cannot be spent until the order fills, is cancelled or expires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenBalance {
+    pub available: Decimal,
+    pub locked: Decimal,
+}
+
+impl TokenBalance {
+    pub fn total(&self) -> Decimal {
+        self.available + self.locked
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UserBal {
-    pub balances: HashMap<String, Decimal>, //token symbol -> balance
+    pub balances: HashMap<String, TokenBalance>, //token symbol -> balance
 }
 
 impl UserBal {
@@ -16,67 +33,608 @@ impl UserBal {
     pub fn get_balance(&self, token_symbol: &str) -> Decimal {
         self.balances
             .get(token_symbol)
-            .copied()
+            .map(|b| b.available)
             .unwrap_or(Decimal::ZERO)
     }
 
-    pub fn has_sufficient_balance(&mut self, token_symbol: &str, amount: Decimal) -> bool {
-        self.get_balance(token_symbol) >= amount
+    pub fn get_locked(&self, token_symbol: &str) -> Decimal {
+        self.balances
+            .get(token_symbol)
+            .map(|b| b.locked)
+            .unwrap_or(Decimal::ZERO)
     }
 
-    pub fn subtract_balance(&mut self, token_symbol: &str, amount: Decimal) -> Result<(), String> {
-        let current = self.get_balance(token_symbol);
-        if current < amount {
-            return Err(format!(
-                "Insufficient balance has {}, needs {}",
-                token_symbol, amount
-            ));
-        }
-
-        self.balances
-            .insert(token_symbol.to_string(), current - amount);
-        Ok(())
+    pub fn has_sufficient_balance(&self, token_symbol: &str, amount: Decimal) -> bool {
+        self.get_balance(token_symbol) >= amount
     }
 }
 
+/// Balance movements for a single fill between a buyer and a seller.
+#[derive(Debug, Clone)]
+pub struct Settlement<'a> {
+    pub buyer_id: &'a str,
+    pub seller_id: &'a str,
+    pub base_token: &'a str,
+    pub quote_token: &'a str,
+    /// Base quantity delivered to the buyer.
+    pub base_amount: Decimal,
+    /// Quote paid to the seller.
+    pub quote_amount: Decimal,
+    /// Quote the buyer had locked for this quantity. Anything above
+    /// `quote_amount` (price improvement, rounding dust) goes back to the
+    /// buyer's available balance.
+    pub quote_reserved: Decimal,
+    pub trade_id: Uuid,
+    pub timestamp: i64,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct BalanceManager {
     user_balances: HashMap<String, UserBal>,
+    /// Every change to `user_balances`, as balanced transfers.
+    ledger: Ledger,
+    /// (user, token) pairs changed since the last `take_changes`.
+    #[serde(skip)]
+    changed: HashSet<(String, String)>,
 }
 
 impl BalanceManager {
     pub fn new() -> Self {
         Self {
             user_balances: HashMap::new(),
+            ledger: Ledger::default(),
+            changed: HashSet::new(),
+        }
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn ledger_mut(&mut self) -> &mut Ledger {
+        &mut self.ledger
+    }
+
+    /// Applies `postings` and records them in the ledger as one transfer.
+    /// Callers check balances first; postings must sum to zero per token.
+    /// Nothing changes if any account would overflow.
+    fn transfer(&mut self, cause: TransferCause, postings: &[Posting]) -> Result<(), String> {
+        debug_assert!(
+            postings.iter().all(|p| postings
+                .iter()
+                .filter(|q| q.token == p.token)
+                .map(|q| q.amount)
+                .sum::<Decimal>()
+                .is_zero()),
+            "Unbalanced transfer {:?}",
+            postings
+        );
+        let postings: Vec<&Posting> = postings.iter().filter(|p| !p.amount.is_zero()).collect();
+        if postings.is_empty() {
+            return Ok(());
+        }
+
+        self.balances_after(&postings)?;
+
+        let transfer_id = self.ledger.begin_transfer();
+        for posting in postings {
+            let balance = match posting.account {
+                Account::External => self
+                    .ledger
+                    .external_balance_mut(posting.user_id, posting.token),
+                account => {
+                    self.mark_changed(posting.user_id, posting.token);
+                    let balance = self
+                        .user_balances
+                        .entry(posting.user_id.to_string())
+                        .or_default()
+                        .balances
+                        .entry(posting.token.to_string())
+                        .or_default();
+                    if account == Account::Available {
+                        &mut balance.available
+                    } else {
+                        &mut balance.locked
+                    }
+                }
+            };
+            let before = *balance;
+            *balance += posting.amount;
+            self.ledger.record(transfer_id, &cause, posting, before);
         }
+        Ok(())
+    }
+
+    /// Each account's balance after its posting in `postings`, or an error if
+    /// one would overflow. Nothing is changed.
+    fn balances_after(&self, postings: &[&Posting]) -> Result<Vec<Decimal>, String> {
+        // An account may appear twice, e.g. when a user trades with themself
+        let mut running: HashMap<(&str, &str, Account), Decimal> = HashMap::new();
+        let mut balances_after = Vec::with_capacity(postings.len());
+        for posting in postings {
+            let balance = running
+                .entry((posting.user_id, posting.token, posting.account))
+                .or_insert_with(|| self.account_balance(posting));
+            *balance = balance.checked_add(posting.amount).ok_or_else(|| {
+                format!(
+                    "{} {:?} balance of {} is out of range",
+                    posting.token, posting.account, posting.user_id
+                )
+            })?;
+            balances_after.push(*balance);
+        }
+        Ok(balances_after)
+    }
+
+    fn account_balance(&self, posting: &Posting) -> Decimal {
+        match posting.account {
+            Account::External => self.ledger.external_balance(posting.user_id, posting.token),
+            Account::Available => self
+                .get_user_balance(posting.user_id)
+                .map_or(Decimal::ZERO, |b| b.get_balance(posting.token)),
+            Account::Locked => self
+                .get_user_balance(posting.user_id)
+                .map_or(Decimal::ZERO, |b| b.get_locked(posting.token)),
+        }
+    }
+
+    fn mark_changed(&mut self, user_id: &str, token_symbol: &str) {
+        self.changed
+            .insert((user_id.to_string(), token_symbol.to_string()));
+    }
+
+    /// Drains the (user, token) pairs whose balance changed, sorted.
+    pub fn take_changes(&mut self) -> Vec<(String, String)> {
+        let mut changes: Vec<_> = self.changed.drain().collect();
+        changes.sort();
+        changes
     }
 
     pub fn get_user_balance(&self, user_id: &str) -> Option<&UserBal> {
         self.user_balances.get(user_id)
     }
 
+    /// Credits `amount` from the user's external account.
+    pub fn deposit(
+        &mut self,
+        user_id: &str,
+        token_symbol: &str,
+        amount: Decimal,
+        now: i64,
+    ) -> Result<(), String> {
+        self.transfer(
+            TransferCause {
+                reason: LedgerReason::Deposit,
+                reference_id: None,
+                timestamp: now,
+            },
+            &[
+                Posting {
+                    user_id,
+                    token: token_symbol,
+                    account: Account::External,
+                    amount: -amount,
+                },
+                Posting {
+                    user_id,
+                    token: token_symbol,
+                    account: Account::Available,
+                    amount,
+                },
+            ],
+        )
+    }
+
+    /// Pays `amount` out to the user's external account and charges `fee` to
+    /// the external account of `FEE_ACCOUNT`, as two transfers referencing
+    /// `withdrawal_id`. Both are checked before either is applied.
+    pub fn withdraw(
+        &mut self,
+        user_id: &str,
+        token_symbol: &str,
+        amount: Decimal,
+        fee: Decimal,
+        withdrawal_id: Uuid,
+        now: i64,
+    ) -> Result<(), String> {
+        let available = self
+            .get_user_balance(user_id)
+            .map_or(Decimal::ZERO, |b| b.get_balance(token_symbol));
+        let required = amount
+            .checked_add(fee)
+            .ok_or_else(|| format!("{} withdrawal is out of range", token_symbol))?;
+        if available < required {
+            return Err(format!(
+                "Insufficient {} balance: available {}, required {}",
+                token_symbol, available, required
+            ));
+        }
+
+        let withdrawal = [
+            Posting {
+                user_id,
+                token: token_symbol,
+                account: Account::Available,
+                amount: -amount,
+            },
+            Posting {
+                user_id,
+                token: token_symbol,
+                account: Account::External,
+                amount,
+            },
+        ];
+        let charge = [
+            Posting {
+                user_id,
+                token: token_symbol,
+                account: Account::Available,
+                amount: -fee,
+            },
+            Posting {
+                user_id: FEE_ACCOUNT,
+                token: token_symbol,
+                account: Account::External,
+                amount: fee,
+            },
+        ];
+        let both: Vec<&Posting> = withdrawal.iter().chain(&charge).collect();
+        self.balances_after(&both)?;
+
+        for (reason, postings) in [
+            (LedgerReason::Withdrawal, &withdrawal),
+            (LedgerReason::Fee, &charge),
+        ] {
+            self.transfer(
+                TransferCause {
+                    reason,
+                    reference_id: Some(withdrawal_id),
+                    timestamp: now,
+                },
+                postings,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Moves `amount` from available to locked for `order_id`.
+    pub fn lock_funds(
+        &mut self,
+        user_id: &str,
+        token_symbol: &str,
+        amount: Decimal,
+        order_id: Uuid,
+        now: i64,
+    ) -> Result<(), String> {
+        let available = self
+            .get_user_balance(user_id)
+            .ok_or_else(|| format!("User {} has no balances", user_id))?
+            .get_balance(token_symbol);
+        if available < amount {
+            return Err(format!(
+                "Insufficient {} balance: available {}, required {}",
+                token_symbol, available, amount
+            ));
+        }
+
+        self.move_between(
+            user_id,
+            token_symbol,
+            amount,
+            (Account::Available, Account::Locked),
+            TransferCause {
+                reason: LedgerReason::Lock,
+                reference_id: Some(order_id),
+                timestamp: now,
+            },
+        )
+    }
+
+    /// Moves `amount` from locked back to available for `order_id`.
+    pub fn unlock_funds(
+        &mut self,
+        user_id: &str,
+        token_symbol: &str,
+        amount: Decimal,
+        order_id: Uuid,
+        now: i64,
+    ) -> Result<(), String> {
+        let locked = self
+            .get_user_balance(user_id)
+            .ok_or_else(|| format!("User {} has no balances", user_id))?
+            .get_locked(token_symbol);
+        if locked < amount {
+            return Err(format!(
+                "Cannot unlock {} {}: only {} locked",
+                amount, token_symbol, locked
+            ));
+        }
+
+        self.move_between(
+            user_id,
+            token_symbol,
+            amount,
+            (Account::Locked, Account::Available),
+            TransferCause {
+                reason: LedgerReason::Unlock,
+                reference_id: Some(order_id),
+                timestamp: now,
+            },
+        )
+    }
+
+    fn move_between(
+        &mut self,
+        user_id: &str,
+        token_symbol: &str,
+        amount: Decimal,
+        (from, to): (Account, Account),
+        cause: TransferCause,
+    ) -> Result<(), String> {
+        self.transfer(
+            cause,
+            &[
+                Posting {
+                    user_id,
+                    token: token_symbol,
+                    account: from,
+                    amount: -amount,
+                },
+                Posting {
+                    user_id,
+                    token: token_symbol,
+                    account: to,
+                    amount,
+                },
+            ],
+        )
+    }
+
+    /// Checks that `settlements` could be applied one after another, after
+    /// `sweep_lock` (user, token, amount) moved from available to locked,
+    /// without changing anything. Matching runs this before it touches the
+    /// book, so settling what it matched cannot fail.
+    pub fn check_settlements(
+        &self,
+        sweep_lock: Option<(&str, &str, Decimal)>,
+        settlements: &[Settlement],
+    ) -> Result<(), String> {
+        let mut postings = Vec::with_capacity(2 + 5 * settlements.len());
+        if let Some((user_id, token, amount)) = sweep_lock {
+            postings.push(Posting {
+                user_id,
+                token,
+                account: Account::Available,
+                amount: -amount,
+            });
+            postings.push(Posting {
+                user_id,
+                token,
+                account: Account::Locked,
+                amount,
+            });
+        }
+        for settlement in settlements {
+            if settlement.quote_amount > settlement.quote_reserved {
+                return Err(format!(
+                    "Settlement pays {} {} but only {} was reserved",
+                    settlement.quote_amount, settlement.quote_token, settlement.quote_reserved
+                ));
+            }
+            postings.extend(settlement_postings(settlement));
+        }
+
+        let postings: Vec<&Posting> = postings.iter().filter(|p| !p.amount.is_zero()).collect();
+        let balances_after = self.balances_after(&postings)?;
+        for (posting, after) in postings.iter().zip(balances_after) {
+            if after >= Decimal::ZERO {
+                continue;
+            }
+            let before = after - posting.amount;
+            return Err(match posting.account {
+                Account::Locked => format!(
+                    "{} has {} {} locked, settlement needs {}",
+                    posting.user_id, before, posting.token, -posting.amount
+                ),
+                _ => format!(
+                    "Insufficient {} balance: available {}, required {}",
+                    posting.token, before, -posting.amount
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Applies a fill. Every leg is checked before anything is mutated so a
+    /// failed settlement leaves all balances untouched.
+    pub fn settle(&mut self, settlement: &Settlement) -> Result<(), String> {
+        self.check_settlements(None, std::slice::from_ref(settlement))?;
+        self.transfer(
+            TransferCause {
+                reason: LedgerReason::Fill,
+                reference_id: Some(settlement.trade_id),
+                timestamp: settlement.timestamp,
+            },
+            &settlement_postings(settlement),
+        )
+    }
+
+    /// Seeds `maker_id` with every token. Stamped 0 so the ledger does not
+    /// depend on when the engine started.
     pub fn initialize_market_maker(&mut self, maker_id: &str) {
-        let mut balance = UserBal::new();
-
-        balance
-            .balances
-            .insert("TAN".to_string(), Decimal::new(100_000, 0));
-        balance
-            .balances
-            .insert("KAN".to_string(), Decimal::new(500_000, 0));
-        balance
-            .balances
-            .insert("ADI".to_string(), Decimal::new(100_000, 0));
-        balance
-            .balances
-            .insert("PRA".to_string(), Decimal::new(100_000, 0));
-        balance
-            .balances
-            .insert("SAT".to_string(), Decimal::new(100_000, 0));
-        balance
-            .balances
-            .insert("RAC".to_string(), Decimal::new(100_000, 0));
-
-        self.user_balances.insert(maker_id.to_string(), balance);
+        for (token, amount) in [
+            ("TAN", 100_000),
+            ("KAN", 500_000),
+            ("ADI", 100_000),
+            ("PRA", 100_000),
+            ("SAT", 100_000),
+            ("RAC", 100_000),
+        ] {
+            if let Err(e) = self.deposit(maker_id, token, Decimal::new(amount, 0), 0) {
+                println!("Failed to seed {} for {}: {}", token, maker_id, e);
+            }
+        }
         println!("Initialized market maker balance for {}", maker_id);
     }
 }
+
+/// The ledger postings of one fill: the buyer's reserve is released, the
+/// seller is paid, any unused reserve goes back to the buyer and the base
+/// moves from the seller's lock to the buyer.
+fn settlement_postings<'a>(settlement: &Settlement<'a>) -> [Posting<'a>; 5] {
+    let buyer = |account, token, amount| Posting {
+        user_id: settlement.buyer_id,
+        token,
+        account,
+        amount,
+    };
+    let seller = |account, token, amount| Posting {
+        user_id: settlement.seller_id,
+        token,
+        account,
+        amount,
+    };
+    let quote = settlement.quote_token;
+    let base = settlement.base_token;
+    [
+        buyer(Account::Locked, quote, -settlement.quote_reserved),
+        buyer(
+            Account::Available,
+            quote,
+            settlement.quote_reserved - settlement.quote_amount,
+        ),
+        seller(Account::Available, quote, settlement.quote_amount),
+        seller(Account::Locked, base, -settlement.base_amount),
+        buyer(Account::Available, base, settlement.base_amount),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settlement<'a>(base_amount: i64, quote_amount: i64, quote_reserved: i64) -> Settlement<'a> {
+        Settlement {
+            buyer_id: "alice",
+            seller_id: "bob",
+            base_token: "TAN",
+            quote_token: "KAN",
+            base_amount: Decimal::from(base_amount),
+            quote_amount: Decimal::from(quote_amount),
+            quote_reserved: Decimal::from(quote_reserved),
+            trade_id: Uuid::new_v4(),
+            timestamp: 2,
+        }
+    }
+
+    fn funded_manager() -> BalanceManager {
+        let mut manager = BalanceManager::new();
+        manager
+            .deposit("alice", "KAN", Decimal::from(100), 1)
+            .unwrap();
+        manager.deposit("bob", "TAN", Decimal::from(10), 1).unwrap();
+        manager
+            .lock_funds("alice", "KAN", Decimal::from(24), Uuid::new_v4(), 1)
+            .unwrap();
+        manager
+            .lock_funds("bob", "TAN", Decimal::from(4), Uuid::new_v4(), 1)
+            .unwrap();
+        manager
+    }
+
+    #[test]
+    fn settle_moves_both_legs_and_refunds_unused_reserve() {
+        let mut manager = funded_manager();
+        manager.settle(&settlement(4, 20, 24)).unwrap();
+
+        let alice = manager.get_user_balance("alice").unwrap();
+        assert_eq!(alice.get_balance("KAN"), Decimal::from(80));
+        assert_eq!(alice.get_locked("KAN"), Decimal::ZERO);
+        assert_eq!(alice.get_balance("TAN"), Decimal::from(4));
+        let bob = manager.get_user_balance("bob").unwrap();
+        assert_eq!(bob.get_balance("KAN"), Decimal::from(20));
+        assert_eq!(bob.get_locked("TAN"), Decimal::ZERO);
+    }
+
+    #[test]
+    fn failed_settlement_changes_nothing() {
+        let mut manager = funded_manager();
+        let entries = manager.ledger().user_entries("alice").count();
+
+        // Bob only locked 4 TAN
+        assert!(manager.settle(&settlement(5, 25, 30)).is_err());
+
+        let alice = manager.get_user_balance("alice").unwrap();
+        assert_eq!(alice.get_locked("KAN"), Decimal::from(24));
+        assert_eq!(alice.get_balance("TAN"), Decimal::ZERO);
+        assert_eq!(manager.ledger().user_entries("alice").count(), entries);
+    }
+
+    #[test]
+    fn ledger_entries_sum_to_zero_per_token() {
+        let mut manager = funded_manager();
+        manager.settle(&settlement(4, 20, 24)).unwrap();
+
+        let mut totals: HashMap<&str, Decimal> = HashMap::new();
+        for user_id in ["alice", "bob"] {
+            for entry in manager.ledger().user_entries(user_id) {
+                assert_eq!(entry.after, entry.before + entry.amount);
+                *totals.entry(entry.token.as_str()).or_default() += entry.amount;
+            }
+        }
+        assert_eq!(totals["KAN"], Decimal::ZERO);
+        assert_eq!(totals["TAN"], Decimal::ZERO);
+    }
+
+    #[test]
+    fn settlements_are_checked_together() {
+        let manager = funded_manager();
+
+        // Each fill fits Bob's 4 TAN lock on its own, both together do not
+        let fills = [settlement(3, 9, 9), settlement(2, 6, 6)];
+        assert!(manager.check_settlements(None, &fills[..1]).is_ok());
+        assert!(manager.check_settlements(None, &fills).is_err());
+
+        // A market buyer's sweep lock counts towards what it can pay
+        let market_buy = [settlement(4, 40, 40)];
+        assert!(manager.check_settlements(None, &market_buy).is_err());
+        let sweep_lock = ("alice", "KAN", Decimal::from(16));
+        assert!(
+            manager
+                .check_settlements(Some(sweep_lock), &market_buy)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn withdrawal_pays_out_and_charges_the_fee() {
+        let mut manager = funded_manager();
+        let fee = Decimal::new(1, 2);
+
+        // Alice has 76 KAN available, which cannot cover 76 plus the fee
+        assert!(
+            manager
+                .withdraw("alice", "KAN", Decimal::from(76), fee, Uuid::new_v4(), 2)
+                .is_err()
+        );
+        manager
+            .withdraw("alice", "KAN", Decimal::from(50), fee, Uuid::new_v4(), 2)
+            .unwrap();
+
+        let alice = manager.get_user_balance("alice").unwrap();
+        assert_eq!(alice.get_balance("KAN"), Decimal::new(2599, 2));
+        assert_eq!(
+            manager.ledger().external_balance("alice", "KAN"),
+            Decimal::from(-50)
+        );
+        assert_eq!(manager.ledger().external_balance(FEE_ACCOUNT, "KAN"), fee);
+        let reasons: Vec<LedgerReason> = manager
+            .ledger()
+            .user_entries("alice")
+            .rev()
+            .take(2)
+            .map(|entry| entry.reason)
+            .collect();
+        assert_eq!(reasons, [LedgerReason::Fee, LedgerReason::Withdrawal]);
+    }
+}
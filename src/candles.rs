@@ -0,0 +1,213 @@
+use crate::input::Fill;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Candles kept per interval before the oldest are dropped.
+pub const MAX_STORED_CANDLES: usize = 5_000;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum CandleInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    /// Name used in query strings and stream channels, e.g. `1m`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CandleInterval::OneMinute => "1m",
+            CandleInterval::FiveMinutes => "5m",
+            CandleInterval::OneHour => "1h",
+            CandleInterval::OneDay => "1d",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|interval| interval.as_str() == name)
+    }
+
+    pub fn millis(self) -> i64 {
+        match self {
+            CandleInterval::OneMinute => 60_000,
+            CandleInterval::FiveMinutes => 5 * 60_000,
+            CandleInterval::OneHour => 60 * 60_000,
+            CandleInterval::OneDay => 24 * 60 * 60_000,
+        }
+    }
+
+    /// Start of the bucket containing `timestamp`, which must not be negative.
+    pub fn bucket_start(self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.millis())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candle {
+    /// Unix millis at the start of the interval.
+    pub open_time: i64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub base_volume: Decimal,
+    pub quote_volume: Decimal,
+    pub trade_count: u64,
+}
+
+impl Candle {
+    /// A candle for an interval without trades, flat at the previous close.
+    fn flat(open_time: i64, price: Decimal) -> Self {
+        Self {
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            base_volume: Decimal::ZERO,
+            quote_volume: Decimal::ZERO,
+            trade_count: 0,
+        }
+    }
+}
+
+/// OHLCV candles for one market at every `CandleInterval`. Only intervals that
+/// saw a trade are stored; gaps are filled in when queried.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CandleSeries {
+    candles: HashMap<CandleInterval, BTreeMap<i64, Candle>>,
+}
+
+impl CandleSeries {
+    pub fn record(&mut self, fill: &Fill) {
+        for interval in CandleInterval::ALL {
+            let open_time = interval.bucket_start(fill.timestamp);
+            let series = self.candles.entry(interval).or_default();
+            let candle = series
+                .entry(open_time)
+                .or_insert_with(|| Candle::flat(open_time, fill.price));
+
+            candle.high = candle.high.max(fill.price);
+            candle.low = candle.low.min(fill.price);
+            candle.close = fill.price;
+            candle.base_volume += fill.quantity;
+            candle.quote_volume += fill.price * fill.quantity;
+            candle.trade_count += 1;
+
+            if series.len() > MAX_STORED_CANDLES {
+                series.pop_first();
+            }
+        }
+    }
+
+    /// The candle containing `timestamp`, if anything traded in it.
+    pub fn candle_at(&self, interval: CandleInterval, timestamp: i64) -> Option<Candle> {
+        self.candles
+            .get(&interval)?
+            .get(&interval.bucket_start(timestamp))
+            .cloned()
+    }
+
+    /// Candles whose interval starts within `start..=end`, oldest first.
+    /// Intervals without trades after the first known price are returned flat
+    /// at the previous close.
+    pub fn range(&self, interval: CandleInterval, start: i64, end: i64) -> Vec<Candle> {
+        let Some(series) = self.candles.get(&interval) else {
+            return Vec::new();
+        };
+        let first = interval.bucket_start(start);
+        let last = interval.bucket_start(end);
+
+        let mut last_close = series.range(..first).next_back().map(|(_, c)| c.close);
+        let mut result = Vec::new();
+        let mut open_time = first;
+        while open_time <= last {
+            if let Some(candle) = series.get(&open_time) {
+                last_close = Some(candle.close);
+                result.push(candle.clone());
+            } else if let Some(close) = last_close {
+                result.push(Candle::flat(open_time, close));
+            }
+            open_time += interval.millis();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn fill(timestamp: i64, price: i64, quantity: i64) -> Fill {
+        Fill {
+            trade_id: Uuid::new_v4(),
+            price: Decimal::from(price),
+            quantity: Decimal::from(quantity),
+            maker_order_id: Uuid::new_v4(),
+            taker_order_id: Uuid::new_v4(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn fills_aggregate_per_bucket_and_gaps_are_flat() {
+        let mut series = CandleSeries::default();
+        series.record(&fill(60_000, 5, 2));
+        series.record(&fill(90_000, 7, 1));
+        series.record(&fill(119_999, 4, 1));
+        series.record(&fill(240_000, 6, 3));
+
+        let candles = series.range(CandleInterval::OneMinute, 0, 240_000);
+        assert_eq!(
+            candles,
+            [
+                Candle {
+                    open_time: 60_000,
+                    open: Decimal::from(5),
+                    high: Decimal::from(7),
+                    low: Decimal::from(4),
+                    close: Decimal::from(4),
+                    base_volume: Decimal::from(4),
+                    quote_volume: Decimal::from(21),
+                    trade_count: 3,
+                },
+                Candle::flat(120_000, Decimal::from(4)),
+                Candle::flat(180_000, Decimal::from(4)),
+                Candle {
+                    open_time: 240_000,
+                    open: Decimal::from(6),
+                    high: Decimal::from(6),
+                    low: Decimal::from(6),
+                    close: Decimal::from(6),
+                    base_volume: Decimal::from(3),
+                    quote_volume: Decimal::from(18),
+                    trade_count: 1,
+                },
+            ]
+        );
+
+        // Every fill lands in the same five-minute candle
+        let candle = series
+            .candle_at(CandleInterval::FiveMinutes, 299_999)
+            .unwrap();
+        assert_eq!(candle.open_time, 0);
+        assert_eq!(candle.trade_count, 4);
+        assert_eq!(candle.close, Decimal::from(6));
+    }
+}
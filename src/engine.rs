@@ -1,80 +1,571 @@
-use crate::balance::BalanceManager;
-use crate::input::{Fill, Order, Side};
+use crate::balance::{BalanceManager, Settlement, UserBal};
+use crate::candles::{Candle, CandleInterval};
+use crate::clock::{Clock, IdGenerator, RandomIds, SystemClock};
+use crate::history::HistoryEvent;
+use crate::input::{
+    CancelReason, Fill, Order, OrderStatus, OrderStatusFilter, OrderType, SelfTradePrevention,
+    Side, TimeInForce,
+};
+use crate::journal::{Command, JOURNAL_SYNC_INTERVAL, Journal, JournalEntry};
+use crate::ledger::{Account, LedgerEntry};
 use crate::market::MarketManager;
-use crate::orderbook::Orderbook;
-use crate::token::{TokenRegistry, TradinPair};
-use actix::{Actor, Context, Handler, Message};
-use rust_decimal::Decimal;
+use crate::orderbook::{DepthDiff, Sweep};
+use crate::output::{
+    BalanceResponse, CandleResponse, DepthDiffResponse, Liquidity, OrderResponse, TickerResponse,
+    TradeResponse, UserFillResponse,
+};
+use crate::snapshot::{
+    EngineState, SNAPSHOT_INTERVAL, Snapshot, load_latest_snapshot, oldest_snapshot_sequence,
+    state_hash, write_snapshot,
+};
+use crate::stream::{MarketDataHub, Publish, user_channel};
+use crate::ticker::Ticker;
+use crate::token::{TokenRegistry, TradingPair};
+use crate::trade_spill::{ReadSpilledTrades, SpillTrades, TradeSpill};
+use crate::trades::Trade;
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, Recipient, ResponseFuture};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use uuid::Uuid;
 
-#[derive(Message, Debug)]
-#[rtype(result = "Result<Uuid, String>")]
+/// How often resting GTD orders are checked for expiry.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Largest page `GetUserOrders` will return.
+pub const MAX_ORDERS_PAGE_SIZE: usize = 500;
+
+/// Most levels per side `GetMarketDepth` will return when given a limit.
+pub const MAX_DEPTH_LEVELS: usize = 5000;
+
+/// Largest price, quantity or deposit the engine accepts, 10^12. Keeps
+/// `price * quantity` and balance sums far inside `Decimal`'s range.
+pub const MAX_AMOUNT: Decimal = Decimal::from_parts(0xD4A5_1000, 0xE8, 0, false, 0);
+
+/// Largest page `GetLedger` will return.
+pub const MAX_LEDGER_PAGE_SIZE: usize = 500;
+
+/// Largest page `GetTrades` will return.
+pub const MAX_TRADES_PAGE_SIZE: usize = 1000;
+
+/// Most candles `GetCandles` will return, and how many it returns when no
+/// `start` is given.
+pub const MAX_CANDLES: i64 = 1000;
+pub const DEFAULT_CANDLES: i64 = 500;
+
+/// Why the engine turned down a request. Routes map each kind to a status
+/// code; the message is for the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    /// The request is invalid, or cannot be applied to the current state.
+    Rejected(String),
+    /// The market, order or user it names does not exist.
+    NotFound(String),
+    /// What it asks for is no longer kept, such as old depth diffs.
+    Gone(String),
+    /// It needs a feature that is not enabled, such as snapshots.
+    Unavailable(String),
+    /// The engine failed to serve it, for example on a journal write.
+    Internal(String),
+}
+
+impl EngineError {
+    pub fn message(&self) -> &str {
+        match self {
+            EngineError::Rejected(message)
+            | EngineError::NotFound(message)
+            | EngineError::Gone(message)
+            | EngineError::Unavailable(message)
+            | EngineError::Internal(message) => message,
+        }
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Engine internals report failures as plain messages, which reject the
+/// request.
+impl From<String> for EngineError {
+    fn from(message: String) -> Self {
+        EngineError::Rejected(message)
+    }
+}
+
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<Uuid, EngineError>")]
 pub struct CreateMarketOrder {
     pub user_id: String,
     pub market: String,
     pub side: Side,
-    pub price: Decimal,
+    pub order_type: OrderType,
+    /// Required for limit orders, ignored for market orders.
+    pub price: Option<Decimal>,
     pub quantity: Decimal,
+    /// Market orders only: how far from the best opposite price the sweep
+    /// may go, in basis points.
+    pub max_slippage_bps: Option<u32>,
+    /// Market orders only: absolute worst price the sweep may reach.
+    pub worst_price: Option<Decimal>,
+    pub time_in_force: TimeInForce,
+    /// Required for GTD orders, unix millis.
+    pub expires_at: Option<i64>,
+    pub post_only: bool,
+    /// Post-only orders that would cross are moved one tick behind the best
+    /// opposite price instead of being rejected.
+    pub post_only_reprice: bool,
+    /// Required for stop and stop-limit orders.
+    pub stop_price: Option<Decimal>,
+    /// Makes a resting limit order an iceberg showing this much at a time.
+    pub display_quantity: Option<Decimal>,
+    pub self_trade_prevention: SelfTradePrevention,
 }
 
 #[derive(Message)]
-#[rtype(result = "Result<crate::output::DepthResponse, String>")]
+#[rtype(result = "Result<crate::output::DepthResponse, EngineError>")]
 pub struct GetMarketDepth {
     pub market_pair: String,
+    pub limit: Option<usize>,
+    pub step: Option<Decimal>,
+}
+
+/// Public trades of a market, oldest first: the latest `limit`, or `limit`
+/// starting at sequence `from_id`.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<Trade>, EngineError>")]
+pub struct GetTrades {
+    pub market_pair: String,
+    pub limit: usize,
+    pub from_id: Option<u64>,
+}
+
+/// Candles of a market whose interval starts between `start` and `end`
+/// (unix millis). `end` defaults to now and `start` to `DEFAULT_CANDLES`
+/// intervals before it.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<Candle>, EngineError>")]
+pub struct GetCandles {
+    pub market_pair: String,
+    pub interval: CandleInterval,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Ticker, EngineError>")]
+pub struct GetTicker {
+    pub market_pair: String,
+}
+
+/// Tickers of every market, sorted by pair.
+#[derive(Message)]
+#[rtype(result = "Vec<Ticker>")]
+pub struct GetTickers;
+
+/// Depth diffs of a market from sequence `from_id` onwards.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<DepthDiff>, EngineError>")]
+pub struct GetDepthDiffs {
+    pub market_pair: String,
+    pub from_id: u64,
 }
 
 #[derive(Message)]
-#[rtype(result = "Result<Order, String>")]
+#[rtype(result = "Result<Order, EngineError>")]
 pub struct GetOrder {
     pub order_id: Uuid,
 }
 
-#[derive(Message)]
-#[rtype(result = "Result<Uuid, String>")]
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<Uuid, EngineError>")]
 pub struct CancelOrder {
     pub order_id: Uuid,
     pub user_id: String,
 }
 
+/// Changes a resting order's price and/or total quantity. `None` keeps the
+/// current value.
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<Uuid, EngineError>")]
+pub struct AmendOrder {
+    pub order_id: Uuid,
+    pub user_id: String,
+    pub price: Option<Decimal>,
+    pub quantity: Option<Decimal>,
+}
+
+/// Cancels every open order of `user_id`, optionally narrowed to one market
+/// and/or side. Returns the cancelled order IDs.
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<Vec<Uuid>, EngineError>")]
+pub struct CancelAllOrders {
+    pub user_id: String,
+    pub market: Option<String>,
+    pub side: Option<Side>,
+}
+
+/// A user's orders, newest first, optionally filtered by status and market.
+/// `cursor` continues from the `next_cursor` of a previous page.
+#[derive(Message)]
+#[rtype(result = "Result<OrderPage, EngineError>")]
+pub struct GetUserOrders {
+    pub user_id: String,
+    pub status: Option<OrderStatusFilter>,
+    pub market: Option<String>,
+    pub cursor: Option<usize>,
+    pub limit: usize,
+}
+
+pub struct OrderPage {
+    pub orders: Vec<Order>,
+    /// Present when older orders may remain.
+    pub next_cursor: Option<usize>,
+}
+
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<(), EngineError>")]
+pub struct Deposit {
+    pub user_id: String,
+    pub token: String,
+    pub amount: Decimal,
+}
+
+/// Pays `amount` out of the user's available balance, plus the token's
+/// withdrawal fee. Returns the withdrawal's ID.
+#[derive(Message, Debug, Clone, Serialize, Deserialize)]
+#[rtype(result = "Result<Uuid, EngineError>")]
+pub struct Withdraw {
+    pub user_id: String,
+    pub token: String,
+    pub amount: Decimal,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<UserBal, EngineError>")]
+pub struct GetBalances {
+    pub user_id: String,
+}
+
+/// A user's ledger entries still held in memory, newest first, optionally
+/// for one token. `cursor` continues from the `next_cursor` of a previous
+/// page.
+#[derive(Message)]
+#[rtype(result = "Result<LedgerPage, EngineError>")]
+pub struct GetLedger {
+    pub user_id: String,
+    pub token: Option<String>,
+    pub cursor: Option<u64>,
+    pub limit: usize,
+}
+
+pub struct LedgerPage {
+    pub entries: Vec<LedgerEntry>,
+    /// Entry ID to continue before; present when older entries may remain.
+    pub next_cursor: Option<u64>,
+}
+
+/// Hash of the engine state and the journal entry it reflects.
+#[derive(Message)]
+#[rtype(result = "Result<(u64, String), EngineError>")]
+pub struct GetStateHash;
+
+/// Writes a snapshot now instead of waiting for the timer.
 #[derive(Message)]
-#[rtype(result = "Result<crate::output::DepthResponse, String>")]
-pub struct GetDepth;
+#[rtype(result = "Result<SnapshotInfo, EngineError>")]
+pub struct TakeSnapshot;
 
-///// implement more order message like cancel order, cancel all orders, get open order, get open orders, get depth, cancel all orders
+pub struct SnapshotInfo {
+    pub journal_sequence: u64,
+    pub taken_at: i64,
+    pub path: PathBuf,
+}
+
+///// implement more order message like get open order
 
 pub struct MatchingEngine {
     pub token_registry: TokenRegistry,
     pub market_manager: MarketManager,
     pub balance_manager: BalanceManager,
-    pub orderbook: Orderbook,
-    orders: std::collections::HashMap<Uuid, Order>,
+    orders: HashMap<Uuid, Order>,
+    /// Every order each user has placed, oldest first. Positions never move,
+    /// so they double as pagination cursors.
+    user_orders: HashMap<String, Vec<Uuid>>,
+    /// Resting GTD orders keyed by (expires_at, order_id).
+    gtd_expiries: BTreeSet<(i64, Uuid)>,
+    /// Receives public market data for WebSocket subscribers, when set.
+    market_data: Option<Addr<MarketDataHub>>,
+    /// Receives closed orders, trades and balance changes, when set.
+    history: Option<Recipient<HistoryEvent>>,
+    /// Keeps trades that fall out of the in-memory trade logs, when set.
+    trade_spill: Option<Addr<TradeSpill>>,
+    /// Every state-changing command is written here before it is applied.
+    journal: Option<Journal>,
+    /// Sequence of the last journal entry applied, 0 before the first.
+    journal_sequence: u64,
+    /// Where snapshots are written, when enabled.
+    snapshot_dir: Option<PathBuf>,
+    /// Journal sequence of the last snapshot written by this process.
+    last_snapshot_sequence: Option<u64>,
+    /// Every timestamp and ID the engine assigns comes from these, so a
+    /// replay with deterministic sources reproduces a run exactly.
+    clock: Box<dyn Clock>,
+    ids: Box<dyn IdGenerator>,
 }
 
 impl MatchingEngine {
     pub fn new() -> Self {
+        Self::with_sources(Box::new(SystemClock), Box::new(RandomIds))
+    }
+
+    /// An engine that takes the time from `clock` and order and trade IDs
+    /// from `ids`.
+    pub fn with_sources(clock: Box<dyn Clock>, ids: Box<dyn IdGenerator>) -> Self {
         let mut engine = Self {
-            token_registry: TokenRegistry::new(),
-            market_manager: MarketManager::new(),
+            token_registry: TokenRegistry::default(),
+            market_manager: MarketManager::default(),
             balance_manager: BalanceManager::new(),
-            orderbook: Orderbook::new(),
-            orders: std::collections::HashMap::new(),
+            orders: HashMap::new(),
+            user_orders: HashMap::new(),
+            gtd_expiries: BTreeSet::new(),
+            market_data: None,
+            history: None,
+            trade_spill: None,
+            journal: None,
+            journal_sequence: 0,
+            snapshot_dir: None,
+            last_snapshot_sequence: None,
+            clock,
+            ids,
         };
 
         // Initialize market maker with liquidity
         engine
             .balance_manager
             .initialize_market_maker("market_maker_1");
+        // Nobody can be subscribed yet; the ledger entries wait for the
+        // history sink
+        engine.balance_manager.take_changes();
         engine.provide_initial_liquidity();
         engine
     }
 
+    pub fn set_market_data_hub(&mut self, hub: Addr<MarketDataHub>) {
+        self.market_data = Some(hub);
+    }
+
+    /// Unlike the hub, attach before restoring and replaying: history a crash
+    /// kept from the sink is written again, replacing the same rows.
+    pub fn set_history_sink(&mut self, sink: Recipient<HistoryEvent>) {
+        self.history = Some(sink);
+    }
+
+    /// Also attach after replay: trades evicted before then are already
+    /// spilled, or were dropped when spilling was off.
+    pub fn set_trade_spill(&mut self, spill: Addr<TradeSpill>) {
+        self.trade_spill = Some(spill);
+    }
+
+    /// Rebuilds state by replaying the journal at `path`, then journals every
+    /// command from here on. Call before attaching the market data hub so the
+    /// replay is not streamed. Returns how many commands were replayed.
+    /// Entries already covered by a restored snapshot are skipped.
+    pub fn open_journal(&mut self, path: &Path) -> io::Result<usize> {
+        let (mut journal, entries) = Journal::open(path)?;
+        let replayed = self.replay_entries(entries)?;
+        journal.continue_after(self.journal_sequence);
+        self.journal = Some(journal);
+        Ok(replayed)
+    }
+
+    /// Applies the journal at `path` without attaching it, leaving the file
+    /// untouched. With deterministic sources (see `with_sources`) two replays
+    /// of the same journal end in the same `state_hash`.
+    pub fn replay_journal(&mut self, path: &Path) -> io::Result<usize> {
+        self.replay_entries(Journal::read(path)?)
+    }
+
+    /// Fails on a gap after the current state, such as journal segments
+    /// deleted after a snapshot that was then lost, and on an entry whose
+    /// replay panics. The engine is left half-applied then and must not be
+    /// used.
+    fn replay_entries(&mut self, entries: Vec<JournalEntry>) -> io::Result<usize> {
+        let mut replayed = 0;
+        for entry in entries {
+            if entry.sequence > self.journal_sequence + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Journal skips from entry {} to {}; restore a snapshot that covers the gap",
+                        self.journal_sequence, entry.sequence
+                    ),
+                ));
+            }
+            if entry.sequence > self.journal_sequence {
+                // Caught only to name the entry; the state it left is dropped
+                let sequence = entry.sequence;
+                if panic::catch_unwind(AssertUnwindSafe(|| self.replay(entry))).is_err() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Replaying journal entry {} panicked", sequence),
+                    ));
+                }
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    /// Last journal entry applied, 0 before the first.
+    pub fn journal_sequence(&self) -> u64 {
+        self.journal_sequence
+    }
+
+    /// Hash of all engine state; see `snapshot::state_hash`.
+    pub fn state_hash(&self) -> Result<String, String> {
+        state_hash(&self.state()).map_err(|e| e.to_string())
+    }
+
+    fn state(&self) -> EngineState<'_> {
+        EngineState {
+            token_registry: Cow::Borrowed(&self.token_registry),
+            market_manager: Cow::Borrowed(&self.market_manager),
+            balance_manager: Cow::Borrowed(&self.balance_manager),
+            orders: Cow::Borrowed(&self.orders),
+            user_orders: Cow::Borrowed(&self.user_orders),
+            gtd_expiries: Cow::Borrowed(&self.gtd_expiries),
+        }
+    }
+
+    /// Replaces all state with the newest intact snapshot in `dir`, if any,
+    /// and returns the journal sequence it was taken at. Call before
+    /// `open_journal` so only the journal tail is replayed.
+    pub fn restore_snapshot(&mut self, dir: &Path) -> io::Result<Option<u64>> {
+        let Some(snapshot) = load_latest_snapshot(dir)? else {
+            return Ok(None);
+        };
+        let state = snapshot.state;
+        self.token_registry = state.token_registry.into_owned();
+        self.market_manager = state.market_manager.into_owned();
+        self.balance_manager = state.balance_manager.into_owned();
+        self.orders = state.orders.into_owned();
+        self.user_orders = state.user_orders.into_owned();
+        self.gtd_expiries = state.gtd_expiries.into_owned();
+        self.ids.resume_at(snapshot.id_position);
+        self.journal_sequence = snapshot.journal_sequence;
+        self.last_snapshot_sequence = Some(snapshot.journal_sequence);
+        Ok(Some(snapshot.journal_sequence))
+    }
+
+    /// Snapshots are written to `dir` every `SNAPSHOT_INTERVAL` and on
+    /// `TakeSnapshot`.
+    pub fn enable_snapshots(&mut self, dir: PathBuf) {
+        self.snapshot_dir = Some(dir);
+    }
+
+    fn take_snapshot(&mut self) -> Result<SnapshotInfo, EngineError> {
+        let dir = self
+            .snapshot_dir
+            .clone()
+            .ok_or_else(|| EngineError::Unavailable("Snapshots are not enabled".to_string()))?;
+        // Never let a snapshot get ahead of what the journal has on disk
+        if let Some(journal) = &mut self.journal {
+            journal
+                .sync()
+                .map_err(|e| EngineError::Internal(format!("Journal sync failed: {}", e)))?;
+        }
+
+        let snapshot = Snapshot {
+            journal_sequence: self.journal_sequence,
+            taken_at: self.clock.now_millis(),
+            id_position: self.ids.position(),
+            state: self.state(),
+        };
+        let path = write_snapshot(&dir, &snapshot)
+            .map_err(|e| EngineError::Internal(format!("Snapshot write failed: {}", e)))?;
+        let info = SnapshotInfo {
+            journal_sequence: snapshot.journal_sequence,
+            taken_at: snapshot.taken_at,
+            path,
+        };
+        self.last_snapshot_sequence = Some(info.journal_sequence);
+
+        // The snapshot is on disk either way, so journal cleanup only logs
+        if let Some(journal) = &mut self.journal {
+            let cleanup = journal
+                .rotate()
+                .and_then(|()| match oldest_snapshot_sequence(&dir)? {
+                    Some(oldest) => journal.remove_segments_through(oldest),
+                    None => Ok(()),
+                });
+            if let Err(e) = cleanup {
+                println!("Journal rotation failed: {}", e);
+            }
+        }
+        Ok(info)
+    }
+
+    fn replay(&mut self, entry: JournalEntry) {
+        self.journal_sequence = entry.sequence;
+        let now = entry.timestamp;
+        // Commands that failed when first applied fail the same way again
+        let _ = match entry.command {
+            Command::CreateOrder { order_id, order } => {
+                // Keep the generator in step with the original run; the
+                // recorded ID wins so it survives a change of generator
+                self.ids.next_id();
+                self.create_order(order, order_id, now).map(drop)
+            }
+            Command::CancelOrder(msg) => self.cancel_order(msg, now).map(drop),
+            Command::CancelAllOrders(msg) => self.cancel_all_orders(msg, now).map(drop),
+            Command::AmendOrder(msg) => self.amend_order(msg, now).map(drop),
+            Command::Deposit(msg) => self.deposit(msg, now),
+            Command::Withdraw {
+                withdrawal_id,
+                withdrawal,
+            } => {
+                self.ids.next_id();
+                self.withdraw(withdrawal, withdrawal_id, now)
+            }
+            Command::ExpireOrders => {
+                self.expire_orders(now);
+                Ok(())
+            }
+        };
+    }
+
+    /// Journals `command` ahead of applying it. Without a journal this is a
+    /// no-op; if the write fails the command must not be applied.
+    fn write_ahead(&mut self, now: i64, command: Command) -> Result<(), String> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+        self.journal_sequence = journal
+            .append(now, &command)
+            .map_err(|e| format!("Journal write failed: {}", e))?;
+        Ok(())
+    }
+
+    fn sync_journal(&mut self) {
+        if let Some(journal) = &mut self.journal
+            && let Err(e) = journal.sync()
+        {
+            println!("Journal sync failed: {}", e);
+        }
+    }
+
     fn provide_initial_liquidity(&mut self) {
         let liquidity_provisions = vec![
-            ("TAN_KAN", Decimal::new(10_000, 0), Decimal::new(50_000, 0)), // 10k TAN, 50
-            ("ADI_TAN", Decimal::new(5_000, 0), Decimal::new(10_000, 0)),  // 5k ADI, 10k
-            ("PRA_KAN", Decimal::new(3_000, 0), Decimal::new(9_000, 0)),   // 3k PRA, 9k
-            ("RAC_SAT", Decimal::new(2_000, 0), Decimal::new(8_000, 0)),   // 2k RAC, 8k
+            ("TAN_KAN", Decimal::new(10_000, 0), Decimal::new(50_000, 0)), // 10k TAN, 50k KAN
+            ("ADI_PRA", Decimal::new(5_000, 0), Decimal::new(15_000, 0)),  // 5k ADI, 15k PRA
+            ("RAC_SAT", Decimal::new(2_000, 0), Decimal::new(4_000, 0)),   // 2k RAC, 4k SAT
         ];
         for (market, base_amount, quote_amount) in liquidity_provisions {
             if let Some(market_ref) = self.market_manager.get_market_mut(market) {
@@ -85,87 +576,399 @@ impl MatchingEngine {
     }
 }
 
+impl Default for MatchingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Actor for MatchingEngine {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(EXPIRY_CHECK_INTERVAL, |engine, _ctx| {
+            let now = engine.clock.now_millis();
+            if engine
+                .gtd_expiries
+                .first()
+                .is_some_and(|(expires_at, _)| *expires_at <= now)
+            {
+                match engine.write_ahead(now, Command::ExpireOrders) {
+                    Ok(()) => engine.expire_orders(now),
+                    Err(e) => println!("Skipping GTD expiry: {}", e),
+                }
+            }
+            // Also catches balance changes left by a request that failed midway
+            engine.publish_balance_changes();
+        });
+        ctx.run_interval(JOURNAL_SYNC_INTERVAL, |engine, _ctx| engine.sync_journal());
+        ctx.run_interval(SNAPSHOT_INTERVAL, |engine, _ctx| {
+            // Nothing to do when snapshots are off or nothing was journaled
+            if engine.snapshot_dir.is_none()
+                || (engine.journal.is_some()
+                    && engine.last_snapshot_sequence == Some(engine.journal_sequence))
+            {
+                return;
+            }
+            match engine.take_snapshot() {
+                Ok(info) => println!("Wrote snapshot {}", info.path.display()),
+                Err(e) => println!("Snapshot failed: {}", e),
+            }
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.sync_journal();
+    }
 }
 
+// --- Journaled Commands ---
+// Each handler journals its command, then applies it. Replaying the journal
+// calls the same apply methods. Amounts are range-checked before journaling
+// so a command that cannot be applied safely never reaches the journal.
+
 // actor handling the create order message
 impl Handler<CreateMarketOrder> for MatchingEngine {
-    type Result = Result<Uuid, String>;
+    type Result = Result<Uuid, EngineError>;
 
     fn handle(&mut self, msg: CreateMarketOrder, _ctx: &mut Self::Context) -> Self::Result {
-        let order_id = Uuid::new_v4();
-        let mut taker_order = Order {
-            order_id,
-            user_id: msg.user_id,
-            side: msg.side.clone(),
-            price: msg.price,
-            quantity: msg.quantity,
-            filled_quantity: Decimal::ZERO,
-            timestamp: chrono::Utc::now().timestamp_millis(),
-        };
+        check_order_amounts(&msg)?;
+        let now = self.clock.now_millis();
+        let position = self.ids.position();
+        let order_id = self.ids.next_id();
+        let journaled = self.write_ahead(
+            now,
+            Command::CreateOrder {
+                order_id,
+                order: msg.clone(),
+            },
+        );
+        if journaled.is_err() {
+            // Replay only advances the generator for journaled orders
+            self.ids.resume_at(position);
+        }
+        journaled.map_err(EngineError::Internal)?;
+        Ok(self.create_order(msg, order_id, now)?)
+    }
+}
 
-        println!("Engine processing order: {:?}", taker_order.order_id);
-        let fills = self.match_order(&mut taker_order);
+impl Handler<CancelOrder> for MatchingEngine {
+    type Result = Result<Uuid, EngineError>;
 
-        if !fills.is_empty() {
-            println!("Matched {} fills.", fills.len());
-            // TODO: Persist fills to DB and publish to Redis
-        }
+    fn handle(&mut self, msg: CancelOrder, _ctx: &mut Self::Context) -> Self::Result {
+        let now = self.clock.now_millis();
+        self.write_ahead(now, Command::CancelOrder(msg.clone()))
+            .map_err(EngineError::Internal)?;
+        Ok(self.cancel_order(msg, now)?)
+    }
+}
 
-        if taker_order.quantity > taker_order.filled_quantity {
-            self.add_order_to_book(taker_order.clone());
-        }
+impl Handler<CancelAllOrders> for MatchingEngine {
+    type Result = Result<Vec<Uuid>, EngineError>;
 
-        self.orders.insert(order_id, taker_order);
-        Ok(order_id)
+    fn handle(&mut self, msg: CancelAllOrders, _ctx: &mut Self::Context) -> Self::Result {
+        let now = self.clock.now_millis();
+        self.write_ahead(now, Command::CancelAllOrders(msg.clone()))
+            .map_err(EngineError::Internal)?;
+        Ok(self.cancel_all_orders(msg, now)?)
     }
 }
 
-impl Handler<CreateMarketOrder> for MatchingEngine {
-    type Result = Result<Uuid, String>;
+impl Handler<AmendOrder> for MatchingEngine {
+    type Result = Result<Uuid, EngineError>;
+
+    fn handle(&mut self, msg: AmendOrder, _ctx: &mut Self::Context) -> Self::Result {
+        check_amend_amounts(&msg)?;
+        let now = self.clock.now_millis();
+        self.write_ahead(now, Command::AmendOrder(msg.clone()))
+            .map_err(EngineError::Internal)?;
+        Ok(self.amend_order(msg, now)?)
+    }
+}
+
+impl Handler<Deposit> for MatchingEngine {
+    type Result = Result<(), EngineError>;
+
+    fn handle(&mut self, msg: Deposit, _ctx: &mut Self::Context) -> Self::Result {
+        check_range("amount", msg.amount)?;
+        let now = self.clock.now_millis();
+        self.write_ahead(now, Command::Deposit(msg.clone()))
+            .map_err(EngineError::Internal)?;
+        Ok(self.deposit(msg, now)?)
+    }
+}
+
+impl Handler<Withdraw> for MatchingEngine {
+    type Result = Result<Uuid, EngineError>;
+
+    fn handle(&mut self, msg: Withdraw, _ctx: &mut Self::Context) -> Self::Result {
+        check_range("amount", msg.amount)?;
+        let now = self.clock.now_millis();
+        let position = self.ids.position();
+        let withdrawal_id = self.ids.next_id();
+        let journaled = self.write_ahead(
+            now,
+            Command::Withdraw {
+                withdrawal_id,
+                withdrawal: msg.clone(),
+            },
+        );
+        if journaled.is_err() {
+            self.ids.resume_at(position);
+        }
+        journaled.map_err(EngineError::Internal)?;
+        self.withdraw(msg, withdrawal_id, now)?;
+        Ok(withdrawal_id)
+    }
+}
+
+impl MatchingEngine {
+    fn create_order(
+        &mut self,
+        msg: CreateMarketOrder,
+        order_id: Uuid,
+        now: i64,
+    ) -> Result<Uuid, String> {
+        // Expired GTD makers must not trade, even between timer ticks
+        self.expire_orders(now);
 
-    fn handle(&mut self, msg: CreateMarketOrder, _ctx: &mut Self::Context) -> Self::Result {
         // Validate market exists
         let market = self
             .market_manager
-            .get_market_mut(&msg.market_pair)
-            .ok_or_else(|| format!("Market {} not found", msg.market_pair))?;
+            .get_market_mut(&msg.market)
+            .ok_or_else(|| format!("Market {} not found", msg.market))?;
 
         if !market.is_active {
-            return Err(format!("Market {} is not active", msg.market_pair));
+            return Err(format!("Market {} is not active", msg.market));
         }
 
-        let order_id = Uuid::new_v4();
-        println!(
-            "Processing order {} for market {}",
-            order_id, msg.market_pair
-        );
+        if msg.quantity <= Decimal::ZERO {
+            return Err("Quantity must be positive".to_string());
+        }
+        check_order_amounts(&msg)?;
+
+        if msg.post_only
+            && (msg.order_type != OrderType::Limit
+                || !matches!(msg.time_in_force, TimeInForce::Gtc | TimeInForce::Gtd))
+        {
+            return Err("Post-only orders must be GTC or GTD limit orders".to_string());
+        }
+
+        let expires_at = match msg.time_in_force {
+            TimeInForce::Gtd => {
+                if msg.order_type != OrderType::Limit {
+                    return Err("GTD is only valid for limit orders".to_string());
+                }
+                let expires_at = msg
+                    .expires_at
+                    .ok_or_else(|| "GTD orders require expires_at".to_string())?;
+                if expires_at <= now {
+                    return Err("expires_at must be in the future".to_string());
+                }
+                Some(expires_at)
+            }
+            _ => None,
+        };
+
+        if let Some(display_quantity) = msg.display_quantity {
+            if msg.order_type.is_market()
+                || !matches!(msg.time_in_force, TimeInForce::Gtc | TimeInForce::Gtd)
+            {
+                return Err("Iceberg orders must be GTC or GTD limit orders".to_string());
+            }
+            if display_quantity <= Decimal::ZERO || display_quantity >= msg.quantity {
+                return Err("display_quantity must be positive and below quantity".to_string());
+            }
+        }
+
+        if msg.order_type.is_stop() && msg.time_in_force == TimeInForce::Fok {
+            return Err("FOK is not supported for stop orders".to_string());
+        }
+
+        let (price, limit_price) = match msg.order_type {
+            OrderType::Limit | OrderType::StopLimit => {
+                let mut price = msg
+                    .price
+                    .filter(|p| *p > Decimal::ZERO)
+                    .ok_or_else(|| "Limit orders require a positive price".to_string())?;
+
+                if msg.post_only && market.orderbook.crosses(&msg.side, price) {
+                    if !msg.post_only_reprice {
+                        return Err("Post-only order would take liquidity".to_string());
+                    }
+                    price = match msg.side {
+                        Side::Buy => market.orderbook.best_ask().unwrap() - market.tick_size,
+                        Side::Sell => market.orderbook.best_bid().unwrap() + market.tick_size,
+                    };
+                    if price <= Decimal::ZERO {
+                        return Err("Post-only order cannot be re-priced".to_string());
+                    }
+                }
+                (price, Some(price))
+            }
+            OrderType::Market => {
+                if msg.worst_price.is_some_and(|p| p <= Decimal::ZERO) {
+                    return Err("Worst price must be positive".to_string());
+                }
+                let best_price = match msg.side {
+                    Side::Buy => market.orderbook.best_ask(),
+                    Side::Sell => market.orderbook.best_bid(),
+                }
+                .ok_or_else(|| format!("No liquidity in market {}", msg.market))?;
+
+                let limit = market_price_limit(
+                    &msg.side,
+                    best_price,
+                    msg.max_slippage_bps,
+                    msg.worst_price,
+                );
+                (limit.unwrap_or(Decimal::ZERO), limit)
+            }
+            OrderType::StopMarket => {
+                if msg.worst_price.is_some_and(|p| p <= Decimal::ZERO) {
+                    return Err("Worst price must be positive".to_string());
+                }
+                (msg.worst_price.unwrap_or(Decimal::ZERO), msg.worst_price)
+            }
+        };
+
+        if msg.order_type.is_stop() {
+            let stop_price = msg
+                .stop_price
+                .filter(|p| *p > Decimal::ZERO)
+                .ok_or_else(|| "Stop orders require a positive stop_price".to_string())?;
+            let would_trigger = match msg.side {
+                Side::Buy => stop_price <= market.price,
+                Side::Sell => stop_price >= market.price,
+            };
+            if would_trigger {
+                return Err(format!(
+                    "Stop price {} would trigger immediately at last price {}",
+                    stop_price, market.price
+                ));
+            }
+
+            // A stop-market buy has no price to reserve at until it triggers
+            if !(msg.order_type == OrderType::StopMarket && msg.side == Side::Buy) {
+                let (lock_token, lock_amount) =
+                    reserved_funds(&market.pair, &msg.side, price, msg.quantity)?;
+                self.balance_manager.lock_funds(
+                    &msg.user_id,
+                    lock_token,
+                    lock_amount,
+                    order_id,
+                    now,
+                )?;
+            }
+
+            let stop_order = Order {
+                order_id,
+                user_id: msg.user_id,
+                market: msg.market,
+                side: msg.side,
+                order_type: msg.order_type,
+                time_in_force: msg.time_in_force,
+                post_only: false,
+                stop_price: Some(stop_price),
+                display_quantity: msg.display_quantity,
+                self_trade_prevention: msg.self_trade_prevention,
+                expires_at: None,
+                price,
+                quantity: msg.quantity,
+                filled_quantity: Decimal::ZERO,
+                timestamp: now,
+                status: OrderStatus::New,
+                cancel_reason: None,
+            };
+            market.stop_book.add_order(stop_order.clone());
+            self.user_orders
+                .entry(stop_order.user_id.clone())
+                .or_default()
+                .push(order_id);
+            self.orders.insert(order_id, stop_order);
+            self.publish_order_update(order_id, &[], now);
+            self.publish_balance_changes();
+            return Ok(order_id);
+        }
+
+        if msg.time_in_force == TimeInForce::Fok {
+            let fillable = market
+                .orderbook
+                .sweep(
+                    &msg.side,
+                    msg.quantity,
+                    limit_price,
+                    &msg.user_id,
+                    msg.self_trade_prevention,
+                )
+                .filled;
+            if fillable < msg.quantity {
+                return Err(format!(
+                    "FOK order cannot be fully filled: {} of {} available",
+                    fillable, msg.quantity
+                ));
+            }
+        }
+
+        // Reserve funds up front so a resting order can always settle. Market
+        // buys are funded by `execute_order` once the sweep cost is known.
+        if !(msg.order_type == OrderType::Market && msg.side == Side::Buy) {
+            let (lock_token, lock_amount) =
+                reserved_funds(&market.pair, &msg.side, price, msg.quantity)?;
+            self.balance_manager.lock_funds(
+                &msg.user_id,
+                lock_token,
+                lock_amount,
+                order_id,
+                now,
+            )?;
+        }
 
-        // Here you would implement the full order matching logic
-        // For now, just acknowledge the order
+        let taker_order = Order {
+            order_id,
+            user_id: msg.user_id,
+            market: msg.market,
+            side: msg.side,
+            order_type: msg.order_type,
+            time_in_force: msg.time_in_force,
+            post_only: msg.post_only,
+            stop_price: None,
+            display_quantity: msg.display_quantity,
+            self_trade_prevention: msg.self_trade_prevention,
+            expires_at,
+            price,
+            quantity: msg.quantity,
+            filled_quantity: Decimal::ZERO,
+            timestamp: now,
+            status: OrderStatus::New,
+            cancel_reason: None,
+        };
 
+        let market_pair = taker_order.market.clone();
+        let user_id = taker_order.user_id.clone();
+        self.execute_order(taker_order, now)?;
+        self.user_orders.entry(user_id).or_default().push(order_id);
+        self.run_stop_triggers(&market_pair, now);
+        self.publish_balance_changes();
         Ok(order_id)
     }
 }
 
 impl Handler<GetOrder> for MatchingEngine {
-    type Result = Result<Order, std::string::String>;
+    type Result = Result<Order, EngineError>;
     fn handle(&mut self, msg: GetOrder, _ctx: &mut Self::Context) -> Self::Result {
         self.orders
             .get(&msg.order_id)
             .cloned()
-            .ok_or_else(|| "Order not found".to_string())
+            .ok_or_else(|| EngineError::NotFound("Order not found".to_string()))
     }
 }
 
-impl Handler<CancelOrder> for MatchingEngine {
-    type Result = Result<Uuid, String>;
-    fn handle(&mut self, msg: CancelOrder, _ctx: &mut Self::Context) -> Self::Result {
-        // Take ownership to avoid overlapping mutable borrows of `self`.
+impl MatchingEngine {
+    fn cancel_order(&mut self, msg: CancelOrder, now: i64) -> Result<Uuid, String> {
         let order = self
             .orders
-            .remove(&msg.order_id)
+            .get(&msg.order_id)
             .ok_or_else(|| "Order not found".to_string())?;
 
         // Basic validation: only the user who created the order can cancel it.
@@ -173,191 +976,1885 @@ impl Handler<CancelOrder> for MatchingEngine {
             return Err("User not authorized to cancel this order".to_string());
         }
 
-        self.remove_order_from_book(order.order_id, order.side, order.price);
-
+        self.cancel_open_order(msg.order_id, CancelReason::UserRequested, now)?;
+        self.publish_balance_changes();
         Ok(msg.order_id)
     }
-}
-
-impl Handler<GetDepth> for MatchingEngine {
-    type Result = Result<crate::output::DepthResponse, String>;
 
-    fn handle(&mut self, _msg: GetDepth, _ctx: &mut Self::Context) -> Self::Result {
-        let bids = self
-            .orderbook
-            .bids
-            .iter()
-            .map(|(price, orders)| {
-                let total_quantity: Decimal =
-                    orders.iter().map(|o| o.quantity - o.filled_quantity).sum();
-                (price.0.to_string(), total_quantity.to_string())
-            })
+    fn cancel_all_orders(&mut self, msg: CancelAllOrders, now: i64) -> Result<Vec<Uuid>, String> {
+        let mut candidates: Vec<(i64, Uuid)> = self
+            .user_orders
+            .get(&msg.user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|order_id| self.orders.get(order_id))
+            .filter(|o| o.is_open())
+            .filter(|o| msg.market.as_ref().is_none_or(|m| &o.market == m))
+            .filter(|o| msg.side.as_ref().is_none_or(|s| &o.side == s))
+            .map(|o| (o.timestamp, o.order_id))
             .collect();
+        candidates.sort();
 
-        let asks = self
-            .orderbook
-            .asks
-            .iter()
-            .map(|(price, orders)| {
-                let total_quantity: Decimal =
-                    orders.iter().map(|o| o.quantity - o.filled_quantity).sum();
-                (price.to_string(), total_quantity.to_string())
+        // Orders that are no longer open are simply skipped
+        let cancelled = candidates
+            .into_iter()
+            .filter(|(_, order_id)| {
+                self.cancel_open_order(*order_id, CancelReason::MassCancel, now)
+                    .is_ok()
             })
+            .map(|(_, order_id)| order_id)
             .collect();
-
-        Ok(crate::output::DepthResponse { bids, asks })
+        self.publish_balance_changes();
+        Ok(cancelled)
     }
-}
-impl Handler<GetMarketDepth> for MatchingEngine {
-    type Result = Result<crate::output::DepthResponse, String>;
 
-    fn handle(&mut self, msg: GetMarketDepth, _ctx: &mut Self::Context) -> Self::Result {
+    fn amend_order(&mut self, msg: AmendOrder, now: i64) -> Result<Uuid, String> {
+        self.expire_orders(now);
+
+        let order = self
+            .orders
+            .get(&msg.order_id)
+            .ok_or_else(|| "Order not found".to_string())?;
+
+        if order.user_id != msg.user_id {
+            return Err("User not authorized to amend this order".to_string());
+        }
+        if !order.is_open() {
+            return Err("Order is no longer open".to_string());
+        }
+
+        let new_price = msg.price.unwrap_or(order.price);
+        let new_quantity = msg.quantity.unwrap_or(order.quantity);
+        if new_price <= Decimal::ZERO {
+            return Err("Price must be positive".to_string());
+        }
+        check_amend_amounts(&msg)?;
+        if new_quantity <= order.filled_quantity {
+            return Err(format!(
+                "Quantity must exceed the filled quantity {}",
+                order.filled_quantity
+            ));
+        }
+        if order
+            .display_quantity
+            .is_some_and(|display| display >= new_quantity)
+        {
+            return Err("Quantity must stay above the iceberg display_quantity".to_string());
+        }
+
         let market = self
             .market_manager
-            .get_market_mut(&msg.market_pair)
-            .ok_or_else(|| format!("Market {} not found", msg.market_pair))?;
-
-        // Return market-specific depth
-        let bids = market
+            .get_market_mut(&order.market)
+            .ok_or_else(|| format!("Market {} not found", order.market))?;
+        if market
             .orderbook
-            .bids
-            .iter()
-            .map(|(price, orders)| {
-                let total_quantity: Decimal =
-                    orders.iter().map(|o| o.quantity - o.filled_quantity).sum();
-                (price.0.to_string(), total_quantity.to_string())
-            })
-            .collect();
+            .get_order_mut(order.order_id, &order.side, order.price)
+            .is_none()
+        {
+            return Err("Order is not resting on the book".to_string());
+        }
 
-        let asks = market
-            .orderbook
-            .asks
-            .iter()
-            .map(|(price, orders)| {
-                let total_quantity: Decimal =
-                    orders.iter().map(|o| o.quantity - o.filled_quantity).sum();
-                (price.to_string(), total_quantity.to_string())
-            })
-            .collect();
+        let keeps_priority = new_price == order.price && new_quantity <= order.quantity;
+        if keeps_priority {
+            // Release the funds first so a failure leaves the order as is
+            let (token, amount) = reserved_funds(
+                &market.pair,
+                &order.side,
+                order.price,
+                order.quantity - new_quantity,
+            )?;
+            self.balance_manager.unlock_funds(
+                &order.user_id,
+                token,
+                amount,
+                order.order_id,
+                now,
+            )?;
+            // Shrinking in place keeps the order's spot in the queue
+            if let Some(book_order) =
+                market
+                    .orderbook
+                    .get_order_mut(order.order_id, &order.side, order.price)
+            {
+                book_order.quantity = new_quantity;
+            }
+            let market_pair = market.pair.pair_symbol.clone();
+            self.orders.get_mut(&msg.order_id).unwrap().quantity = new_quantity;
+            self.commit_book_changes(&market_pair, &[]);
+            self.publish_order_update(msg.order_id, &[], now);
+            self.publish_balance_changes();
+            return Ok(msg.order_id);
+        }
 
-        Ok(crate::output::DepthResponse { bids, asks })
+        if order.post_only && market.orderbook.crosses(&order.side, new_price) {
+            return Err("Post-only order would take liquidity".to_string());
+        }
+
+        // Settle the lock difference first so a failure leaves the order as is
+        let (token, old_reserved) = reserved_funds(
+            &market.pair,
+            &order.side,
+            order.price,
+            order.remaining_quantity(),
+        )?;
+        let (_, new_reserved) = reserved_funds(
+            &market.pair,
+            &order.side,
+            new_price,
+            new_quantity - order.filled_quantity,
+        )?;
+        if new_reserved > old_reserved {
+            self.balance_manager.lock_funds(
+                &order.user_id,
+                token,
+                new_reserved - old_reserved,
+                order.order_id,
+                now,
+            )?;
+        } else {
+            self.balance_manager.unlock_funds(
+                &order.user_id,
+                token,
+                old_reserved - new_reserved,
+                order.order_id,
+                now,
+            )?;
+        }
+
+        market
+            .orderbook
+            .remove_order(order.order_id, &order.side, order.price);
+
+        // Re-entering the book loses time priority and may trade
+        let mut amended = order.clone();
+        amended.price = new_price;
+        amended.quantity = new_quantity;
+        amended.timestamp = now;
+
+        let market_pair = amended.market.clone();
+        self.execute_order(amended, now)?;
+        self.run_stop_triggers(&market_pair, now);
+        self.publish_balance_changes();
+        Ok(msg.order_id)
     }
 }
 
-// --- Core Matching Logic ---
+impl Handler<GetUserOrders> for MatchingEngine {
+    type Result = Result<OrderPage, EngineError>;
 
-impl MatchingEngine {
-    fn add_order_to_book(&mut self, order: Order) {
-        let book_side = match order.side {
-            Side::Buy => self
-                .orderbook
-                .bids
-                .entry(std::cmp::Reverse(order.price))
-                .or_default(),
-            Side::Sell => self.orderbook.asks.entry(order.price).or_default(),
+    fn handle(&mut self, msg: GetUserOrders, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.limit == 0 || msg.limit > MAX_ORDERS_PAGE_SIZE {
+            return Err(EngineError::Rejected(format!(
+                "limit must be between 1 and {}",
+                MAX_ORDERS_PAGE_SIZE
+            )));
+        }
+
+        let order_ids = self
+            .user_orders
+            .get(&msg.user_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let end = msg.cursor.unwrap_or(order_ids.len()).min(order_ids.len());
+
+        let mut orders = Vec::new();
+        let mut next_cursor = None;
+        for position in (0..end).rev() {
+            let Some(order) = self.orders.get(&order_ids[position]) else {
+                continue;
+            };
+            if msg.status.is_some_and(|s| !s.matches(order.status))
+                || msg.market.as_ref().is_some_and(|m| &order.market != m)
+            {
+                continue;
+            }
+            orders.push(order.clone());
+            if orders.len() == msg.limit {
+                next_cursor = (position > 0).then_some(position);
+                break;
+            }
+        }
+
+        Ok(OrderPage {
+            orders,
+            next_cursor,
+        })
+    }
+}
+
+impl Handler<GetMarketDepth> for MatchingEngine {
+    type Result = Result<crate::output::DepthResponse, EngineError>;
+
+    fn handle(&mut self, msg: GetMarketDepth, _ctx: &mut Self::Context) -> Self::Result {
+        if msg
+            .limit
+            .is_some_and(|limit| limit == 0 || limit > MAX_DEPTH_LEVELS)
+        {
+            return Err(EngineError::Rejected(format!(
+                "limit must be between 1 and {}",
+                MAX_DEPTH_LEVELS
+            )));
+        }
+        let market = self
+            .market_manager
+            .get_market_mut(&msg.market_pair)
+            .ok_or_else(|| market_not_found(&msg.market_pair))?;
+        // Buckets finer than a tick merge nothing, and the bounds keep the
+        // bucket arithmetic inside `Decimal`'s range
+        if msg
+            .step
+            .is_some_and(|step| step < market.tick_size || step > MAX_AMOUNT)
+        {
+            return Err(EngineError::Rejected(format!(
+                "step must be between the tick size {} and {}",
+                market.tick_size, MAX_AMOUNT
+            )));
+        }
+
+        // Return market-specific depth
+        Ok(market.orderbook.depth(msg.limit, msg.step))
+    }
+}
+
+impl Handler<GetDepthDiffs> for MatchingEngine {
+    type Result = Result<Vec<DepthDiff>, EngineError>;
+
+    fn handle(&mut self, msg: GetDepthDiffs, _ctx: &mut Self::Context) -> Self::Result {
+        let market = self
+            .market_manager
+            .get_market_mut(&msg.market_pair)
+            .ok_or_else(|| market_not_found(&msg.market_pair))?;
+        // Too far behind: the client has to take a fresh snapshot
+        market
+            .orderbook
+            .diffs_since(msg.from_id)
+            .map_err(EngineError::Gone)
+    }
+}
+
+impl Handler<GetTrades> for MatchingEngine {
+    type Result = ResponseFuture<Result<Vec<Trade>, EngineError>>;
+
+    /// Trades older than the in-memory log are read by the trade spill on
+    /// its own thread while the engine moves on.
+    fn handle(&mut self, msg: GetTrades, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.limit == 0 || msg.limit > MAX_TRADES_PAGE_SIZE {
+            let e = EngineError::Rejected(format!(
+                "limit must be between 1 and {}",
+                MAX_TRADES_PAGE_SIZE
+            ));
+            return Box::pin(async move { Err(e) });
+        }
+        let Some(market) = self.market_manager.get_market_mut(&msg.market_pair) else {
+            let e = market_not_found(&msg.market_pair);
+            return Box::pin(async move { Err(e) });
+        };
+
+        let Some(from_id) = msg.from_id else {
+            let trades = market.trades.latest(msg.limit);
+            return Box::pin(async move { Ok(trades) });
         };
-        book_side.push_back(order);
+        let recent = market.trades.from_sequence(from_id, msg.limit);
+        let oldest_in_memory = market.trades.oldest_sequence();
+        let spill = match &self.trade_spill {
+            Some(spill) if from_id < oldest_in_memory => spill.clone(),
+            _ => return Box::pin(async move { Ok(recent) }),
+        };
+        let read = spill.send(ReadSpilledTrades {
+            market: msg.market_pair,
+            from: from_id,
+            limit: msg.limit,
+        });
+        Box::pin(async move {
+            let mut trades = read
+                .await
+                .map_err(|_| EngineError::Internal("Trade spill mailbox error".to_string()))?
+                .map_err(EngineError::Internal)?;
+            // Anything spilled since this request is also still in `recent`
+            trades.retain(|t| t.sequence < oldest_in_memory);
+            let remaining = msg.limit - trades.len();
+            trades.extend(recent.into_iter().take(remaining));
+            Ok(trades)
+        })
+    }
+}
+
+impl Handler<GetCandles> for MatchingEngine {
+    type Result = Result<Vec<Candle>, EngineError>;
+
+    fn handle(&mut self, msg: GetCandles, _ctx: &mut Self::Context) -> Self::Result {
+        let market = self
+            .market_manager
+            .get_market_mut(&msg.market_pair)
+            .ok_or_else(|| market_not_found(&msg.market_pair))?;
+
+        if msg.start.is_some_and(|t| t < 0) || msg.end.is_some_and(|t| t < 0) {
+            return Err(EngineError::Rejected(
+                "start and end must not be negative".to_string(),
+            ));
+        }
+
+        // Intervals that have not started yet have no candle
+        let now = self.clock.now_millis();
+        let end = msg.end.unwrap_or(now).min(now);
+        let step = msg.interval.millis();
+        let start = msg.start.unwrap_or_else(|| {
+            end.checked_sub((DEFAULT_CANDLES - 1) * step)
+                .map_or(0, |start| start.max(0))
+        });
+        if start > end {
+            return Err(EngineError::Rejected(
+                "start must not be after end".to_string(),
+            ));
+        }
+        let count = msg
+            .interval
+            .bucket_start(end)
+            .checked_sub(msg.interval.bucket_start(start))
+            .map(|span| span / step + 1)
+            .ok_or_else(|| "Candle range is too large".to_string())?;
+        if count > MAX_CANDLES {
+            return Err(EngineError::Rejected(format!(
+                "Range covers {} candles, at most {} can be requested",
+                count, MAX_CANDLES
+            )));
+        }
+
+        Ok(market.candles.range(msg.interval, start, end))
+    }
+}
+
+impl Handler<GetTicker> for MatchingEngine {
+    type Result = Result<Ticker, EngineError>;
+
+    fn handle(&mut self, msg: GetTicker, _ctx: &mut Self::Context) -> Self::Result {
+        let market = self
+            .market_manager
+            .get_market(&msg.market_pair)
+            .ok_or_else(|| market_not_found(&msg.market_pair))?;
+        Ok(market.ticker(self.clock.now_millis()))
+    }
+}
+
+impl Handler<GetTickers> for MatchingEngine {
+    type Result = Vec<Ticker>;
+
+    fn handle(&mut self, _msg: GetTickers, _ctx: &mut Self::Context) -> Self::Result {
+        let now = self.clock.now_millis();
+        let mut tickers: Vec<Ticker> = self
+            .market_manager
+            .markets()
+            .map(|market| market.ticker(now))
+            .collect();
+        tickers.sort_by(|a, b| a.market.cmp(&b.market));
+        tickers
+    }
+}
+
+impl MatchingEngine {
+    fn deposit(&mut self, msg: Deposit, now: i64) -> Result<(), String> {
+        if self.token_registry.get_token(&msg.token).is_none() {
+            return Err(format!("Token {} not found", msg.token));
+        }
+        if msg.amount <= Decimal::ZERO {
+            return Err("Deposit amount must be positive".to_string());
+        }
+        check_range("amount", msg.amount)?;
+
+        self.balance_manager
+            .deposit(&msg.user_id, &msg.token, msg.amount, now)?;
+        self.publish_balance_changes();
+        Ok(())
+    }
+
+    fn withdraw(&mut self, msg: Withdraw, withdrawal_id: Uuid, now: i64) -> Result<(), String> {
+        let fee = self
+            .token_registry
+            .get_token(&msg.token)
+            .ok_or_else(|| format!("Token {} not found", msg.token))?
+            .withdrawal_fee;
+        if msg.amount <= Decimal::ZERO {
+            return Err("Withdrawal amount must be positive".to_string());
+        }
+        check_range("amount", msg.amount)?;
+
+        self.balance_manager.withdraw(
+            &msg.user_id,
+            &msg.token,
+            msg.amount,
+            fee,
+            withdrawal_id,
+            now,
+        )?;
+        self.publish_balance_changes();
+        Ok(())
+    }
+}
+
+impl Handler<GetStateHash> for MatchingEngine {
+    type Result = Result<(u64, String), EngineError>;
+
+    fn handle(&mut self, _msg: GetStateHash, _ctx: &mut Self::Context) -> Self::Result {
+        let state_hash = self.state_hash().map_err(EngineError::Internal)?;
+        Ok((self.journal_sequence, state_hash))
+    }
+}
+
+impl Handler<TakeSnapshot> for MatchingEngine {
+    type Result = Result<SnapshotInfo, EngineError>;
+
+    fn handle(&mut self, _msg: TakeSnapshot, _ctx: &mut Self::Context) -> Self::Result {
+        self.take_snapshot()
+    }
+}
+
+impl Handler<GetLedger> for MatchingEngine {
+    type Result = Result<LedgerPage, EngineError>;
+
+    fn handle(&mut self, msg: GetLedger, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.limit == 0 || msg.limit > MAX_LEDGER_PAGE_SIZE {
+            return Err(EngineError::Rejected(format!(
+                "limit must be between 1 and {}",
+                MAX_LEDGER_PAGE_SIZE
+            )));
+        }
+
+        let mut older = self
+            .balance_manager
+            .ledger()
+            .user_entries(&msg.user_id)
+            .rev()
+            .filter(|entry| msg.cursor.is_none_or(|cursor| entry.entry_id < cursor))
+            .filter(|entry| msg.token.as_ref().is_none_or(|t| &entry.token == t))
+            .peekable();
+
+        let mut entries = Vec::new();
+        while entries.len() < msg.limit
+            && let Some(entry) = older.next()
+        {
+            entries.push(entry.clone());
+        }
+        let next_cursor = older.peek().and(entries.last()).map(|entry| entry.entry_id);
+
+        Ok(LedgerPage {
+            entries,
+            next_cursor,
+        })
+    }
+}
+
+impl Handler<GetBalances> for MatchingEngine {
+    type Result = Result<UserBal, EngineError>;
+
+    fn handle(&mut self, msg: GetBalances, _ctx: &mut Self::Context) -> Self::Result {
+        self.balance_manager
+            .get_user_balance(&msg.user_id)
+            .cloned()
+            .ok_or_else(|| EngineError::NotFound(format!("User {} has no balances", msg.user_id)))
+    }
+}
+
+// --- Balance Helpers ---
+
+/// Token and amount an order reserves for `quantity` of its size: quote for
+/// buys, base for sells.
+fn reserved_funds<'a>(
+    pair: &'a TradingPair,
+    side: &Side,
+    price: Decimal,
+    quantity: Decimal,
+) -> Result<(&'a str, Decimal), String> {
+    match side {
+        Side::Buy => price
+            .checked_mul(quantity)
+            .map(|cost| (pair.quote_tkn.as_str(), cost))
+            .ok_or_else(|| format!("Order value {} x {} is out of range", price, quantity)),
+        Side::Sell => Ok((&pair.base_tkn, quantity)),
+    }
+}
+
+fn market_not_found(market_pair: &str) -> EngineError {
+    EngineError::NotFound(format!("Market {} not found", market_pair))
+}
+
+/// Range checks on every amount an order carries.
+fn check_order_amounts(msg: &CreateMarketOrder) -> Result<(), String> {
+    check_range("quantity", msg.quantity)?;
+    for (name, value) in [
+        ("price", msg.price),
+        ("worst_price", msg.worst_price),
+        ("stop_price", msg.stop_price),
+        ("display_quantity", msg.display_quantity),
+    ] {
+        if let Some(value) = value {
+            check_range(name, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_amend_amounts(msg: &AmendOrder) -> Result<(), String> {
+    for (name, value) in [("price", msg.price), ("quantity", msg.quantity)] {
+        if let Some(value) = value {
+            check_range(name, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rejects `value` unless it is within `MAX_AMOUNT`. Prices, quantities and
+/// deposits are checked before anything is journaled or multiplied.
+fn check_range(name: &str, value: Decimal) -> Result<(), String> {
+    if value.abs() > MAX_AMOUNT {
+        return Err(format!("{} must not exceed {}", name, MAX_AMOUNT));
+    }
+    Ok(())
+}
+
+/// Worst price a market order may trade at, from a band of `max_slippage_bps`
+/// around the best opposite price and/or an absolute `worst_price`. The
+/// tighter bound wins; `None` means the order may sweep the whole book.
+fn market_price_limit(
+    side: &Side,
+    best_price: Decimal,
+    max_slippage_bps: Option<u32>,
+    worst_price: Option<Decimal>,
+) -> Option<Decimal> {
+    let band = max_slippage_bps.map(|bps| {
+        let offset = best_price * Decimal::from(bps) / Decimal::from(10_000);
+        match side {
+            Side::Buy => best_price + offset,
+            Side::Sell => best_price - offset,
+        }
+    });
+
+    match (band, worst_price) {
+        (Some(band), Some(worst)) => Some(match side {
+            Side::Buy => band.min(worst),
+            Side::Sell => band.max(worst),
+        }),
+        (band, worst) => band.or(worst),
     }
+}
 
-    fn remove_order_from_book(&mut self, order_id: Uuid, side: Side, price: Decimal) {
-        let book_side = match side {
-            Side::Buy => self.orderbook.bids.get_mut(&std::cmp::Reverse(price)),
-            Side::Sell => self.orderbook.asks.get_mut(&price),
+impl MatchingEngine {
+    /// Returns the funds still reserved by the unfilled part of `order`.
+    fn release_order_lock(&mut self, order: &Order, now: i64) {
+        let remaining = order.remaining_quantity();
+        if remaining <= Decimal::ZERO {
+            return;
+        }
+        let Some(market) = self.market_manager.get_market_mut(&order.market) else {
+            return;
         };
+        // Market buys lock only the sweep cost, which their fills consume
+        if order.order_type.is_market() && order.side == Side::Buy {
+            return;
+        }
+
+        if let Err(e) = reserved_funds(&market.pair, &order.side, order.price, remaining).and_then(
+            |(token, amount)| {
+                self.balance_manager.unlock_funds(
+                    &order.user_id,
+                    token,
+                    amount,
+                    order.order_id,
+                    now,
+                )
+            },
+        ) {
+            println!("Failed to release lock for order {}: {}", order.order_id, e);
+        }
+    }
+}
+
+/// The balance movements of `fill` between `taker_order` and the resting
+/// maker. The quote leg is rounded down to the quote token's precision; the
+/// remainder stays with the buyer.
+fn settlement<'a>(
+    orders: &'a HashMap<Uuid, Order>,
+    token_registry: &TokenRegistry,
+    pair: &'a TradingPair,
+    fill: &Fill,
+    taker_order: &'a Order,
+) -> Result<Settlement<'a>, String> {
+    let maker_order = orders
+        .get(&fill.maker_order_id)
+        .ok_or_else(|| format!("Maker order {} missing at settlement", fill.maker_order_id))?;
+    let (buyer, seller) = match taker_order.side {
+        Side::Buy => (taker_order, maker_order),
+        Side::Sell => (maker_order, taker_order),
+    };
+
+    let quote_decimals = token_registry
+        .get_token(&pair.quote_tkn)
+        .map(|t| u32::from(t.decimals).min(28))
+        .unwrap_or(28);
+    let quote_amount = (fill.price * fill.quantity)
+        .round_dp_with_strategy(quote_decimals, RoundingStrategy::ToZero);
+
+    // Limit buys reserve at their own price, market buys at each fill price
+    let reserved_price = if buyer.order_type.is_market() {
+        fill.price
+    } else {
+        buyer.price
+    };
+
+    Ok(Settlement {
+        buyer_id: &buyer.user_id,
+        seller_id: &seller.user_id,
+        base_token: &pair.base_tkn,
+        quote_token: &pair.quote_tkn,
+        base_amount: fill.quantity,
+        quote_amount,
+        quote_reserved: reserved_price * fill.quantity,
+        trade_id: fill.trade_id,
+        timestamp: fill.timestamp,
+    })
+}
+
+impl MatchingEngine {
+    /// Fails unless every fill `sweep` plans for `taker_order` could settle,
+    /// after `sweep_lock` is taken from a market buyer. Nothing is changed.
+    fn check_sweep(
+        &self,
+        pair: &TradingPair,
+        sweep: &Sweep,
+        taker_order: &Order,
+        sweep_lock: Option<Decimal>,
+    ) -> Result<(), String> {
+        let fills: Vec<Fill> = sweep
+            .fills
+            .iter()
+            .map(|&(maker_order_id, price, quantity)| Fill {
+                trade_id: Uuid::nil(),
+                price,
+                quantity,
+                maker_order_id,
+                taker_order_id: taker_order.order_id,
+                timestamp: 0,
+            })
+            .collect();
+        let settlements = fills
+            .iter()
+            .map(|fill| settlement(&self.orders, &self.token_registry, pair, fill, taker_order))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.balance_manager.check_settlements(
+            sweep_lock.map(|cost| (taker_order.user_id.as_str(), pair.quote_tkn.as_str(), cost)),
+            &settlements,
+        )
+    }
+
+    /// Moves base and quote between maker and taker for one fill. Orders only
+    /// match once `check_sweep` has passed for all their fills, so a failure
+    /// here means the balances no longer add up; the engine stops rather
+    /// than trade on from there.
+    fn settle_fill(&mut self, pair: &TradingPair, fill: &Fill, taker_order: &Order) {
+        if let Err(e) = settlement(&self.orders, &self.token_registry, pair, fill, taker_order)
+            .and_then(|settlement| self.balance_manager.settle(&settlement))
+        {
+            panic!(
+                "Trade {} failed to settle after its check: {}",
+                fill.trade_id, e
+            );
+        }
+    }
+}
+
+// --- Order Execution ---
+
+impl MatchingEngine {
+    /// Matches a validated, funded order against its market's book, settles
+    /// the fills, then rests or cancels whatever is left according to the
+    /// order's type and time in force. Market buys have no price to reserve
+    /// at, so they are funded here with exactly what the sweep will cost.
+    /// Every fill is checked to settle before the book is touched; if one
+    /// cannot, the order is rejected and its lock released. Fills are
+    /// stamped with `now`.
+    fn execute_order(&mut self, mut taker_order: Order, now: i64) -> Result<Vec<Fill>, String> {
+        let market = self
+            .market_manager
+            .get_market(&taker_order.market)
+            .ok_or_else(|| format!("Market {} not found", taker_order.market))?;
+
+        // Check every fill can settle before the book changes, so settlement
+        // never has to undo a match
+        let limit_price = taker_order.limit_price();
+        let sweep = market.orderbook.sweep(
+            &taker_order.side,
+            taker_order.remaining_quantity(),
+            limit_price,
+            &taker_order.user_id,
+            taker_order.self_trade_prevention,
+        );
+        let sweep_lock = (taker_order.order_type.is_market() && taker_order.side == Side::Buy)
+            .then_some(sweep.cost);
+        if let Err(e) = self.check_sweep(&market.pair, &sweep, &taker_order, sweep_lock) {
+            println!("Rejecting order {}: {}", taker_order.order_id, e);
+            self.release_order_lock(&taker_order, now);
+            if let Some(order) = self.orders.get_mut(&taker_order.order_id) {
+                order.status = OrderStatus::Rejected;
+            }
+            return Err(e);
+        }
+
+        let market = self
+            .market_manager
+            .get_market_mut(&taker_order.market)
+            .ok_or_else(|| format!("Market {} not found", taker_order.market))?;
+        if let Some(cost) = sweep_lock {
+            self.balance_manager.lock_funds(
+                &taker_order.user_id,
+                &market.pair.quote_tkn,
+                cost,
+                taker_order.order_id,
+                now,
+            )?;
+        }
+
+        println!(
+            "Engine processing order {} for market {}",
+            taker_order.order_id, taker_order.market
+        );
+        let original_quantity = taker_order.quantity;
+        let outcome =
+            market
+                .orderbook
+                .match_order(&mut taker_order, limit_price, self.ids.as_mut(), now);
+        let fills = outcome.fills;
 
-        if let Some(orders_at_price) = book_side {
-            orders_at_price.retain(|o| o.order_id != order_id);
-            if orders_at_price.is_empty() {
-                match side {
-                    Side::Buy => self.orderbook.bids.remove(&std::cmp::Reverse(price)),
-                    Side::Sell => self.orderbook.asks.remove(&price),
+        if let Some(last_fill) = fills.last() {
+            market.price = last_fill.price;
+        }
+        // Trim the ticker with the command's own time so replay trims alike
+        market.ticker_stats.evict(now);
+        let mut trades = Vec::with_capacity(fills.len());
+        for fill in &fills {
+            trades.push(
+                market
+                    .trades
+                    .record(&taker_order.market, fill, taker_order.side.clone()),
+            );
+            market.candles.record(fill);
+            market.ticker_stats.record(fill);
+        }
+
+        let remaining = taker_order.remaining_quantity();
+        let rests =
+            remaining > Decimal::ZERO && taker_order.rests_on_book() && !outcome.cancel_taker;
+        if rests {
+            market.orderbook.add_order(taker_order.clone());
+        }
+        let pair = market.pair.clone();
+
+        for fill in &fills {
+            self.settle_fill(&pair, fill, &taker_order);
+
+            // Keep the engine's copy of each maker in step with the book
+            if let Some(maker_order) = self.orders.get_mut(&fill.maker_order_id) {
+                maker_order.filled_quantity += fill.quantity;
+                maker_order.status = if maker_order.quantity == maker_order.filled_quantity {
+                    OrderStatus::Filled
+                } else {
+                    OrderStatus::PartiallyFilled
                 };
             }
+            self.publish_order_update(
+                fill.maker_order_id,
+                &[UserFillResponse::new(fill, Liquidity::Maker)],
+                now,
+            );
+        }
+
+        // Self-trade prevention pulled or shrank these without trading
+        for maker_order in &outcome.cancelled_makers {
+            println!(
+                "Self-trade prevention cancelled order {}",
+                maker_order.order_id
+            );
+            if let Some(order) = self.orders.get_mut(&maker_order.order_id) {
+                order.cancel(CancelReason::SelfTradePrevention);
+            }
+            self.release_order_lock(maker_order, now);
+            self.publish_order_update(maker_order.order_id, &[], now);
+        }
+        for (maker_order_id, decrement) in &outcome.decremented_makers {
+            let Some(maker_order) = self.orders.get_mut(maker_order_id) else {
+                continue;
+            };
+            maker_order.quantity -= *decrement;
+            if let Err(e) = reserved_funds(&pair, &maker_order.side, maker_order.price, *decrement)
+                .and_then(|(token, amount)| {
+                    self.balance_manager.unlock_funds(
+                        &maker_order.user_id,
+                        token,
+                        amount,
+                        *maker_order_id,
+                        now,
+                    )
+                })
+            {
+                println!("Failed to release lock for order {}: {}", maker_order_id, e);
+            }
+            if maker_order.remaining_quantity() == Decimal::ZERO {
+                maker_order.cancel(CancelReason::SelfTradePrevention);
+            }
+            self.publish_order_update(*maker_order_id, &[], now);
+        }
+
+        let taker_decrement = original_quantity - taker_order.quantity;
+        if taker_decrement > Decimal::ZERO
+            && sweep_lock.is_none()
+            && let Err(e) =
+                reserved_funds(&pair, &taker_order.side, taker_order.price, taker_decrement)
+                    .and_then(|(token, amount)| {
+                        self.balance_manager.unlock_funds(
+                            &taker_order.user_id,
+                            token,
+                            amount,
+                            taker_order.order_id,
+                            now,
+                        )
+                    })
+        {
+            println!(
+                "Failed to release lock for order {}: {}",
+                taker_order.order_id, e
+            );
         }
-    }
 
-    fn match_order(&mut self, taker_order: &mut Order) -> Vec<Fill> {
-        let mut fills = Vec::new();
-        let mut orders_to_remove = Vec::new();
+        if rests {
+            if let Some(expires_at) = taker_order.expires_at {
+                self.gtd_expiries.insert((expires_at, taker_order.order_id));
+            }
+        } else if remaining > Decimal::ZERO {
+            println!(
+                "Cancelling unfilled {} of order {}",
+                remaining, taker_order.order_id
+            );
+            self.release_order_lock(&taker_order, now);
+        }
 
-        match taker_order.side {
-            Side::Sell => {
-                for (price, orders_at_price) in self.orderbook.bids.iter_mut() {
-                    if taker_order.price > price.0 {
-                        break;
-                    } // Taker wants to sell for more than buyers are offering
+        // Whatever the sweep reserved but did not spend goes back
+        if let Some(locked) = sweep_lock {
+            let spent: Decimal = fills.iter().map(|f| f.price * f.quantity).sum();
+            if locked > spent
+                && let Err(e) = self.balance_manager.unlock_funds(
+                    &taker_order.user_id,
+                    &pair.quote_tkn,
+                    locked - spent,
+                    taker_order.order_id,
+                    now,
+                )
+            {
+                println!(
+                    "Failed to release lock for order {}: {}",
+                    taker_order.order_id, e
+                );
+            }
+        }
 
-                    for maker_order in orders_at_price.iter_mut() {
-                        if taker_order.filled_quantity >= taker_order.quantity {
-                            break;
-                        }
+        if rests {
+            taker_order.status = taker_order.open_status();
+        } else if remaining == Decimal::ZERO && taker_order.filled_quantity > Decimal::ZERO {
+            taker_order.status = OrderStatus::Filled;
+        } else if outcome.cancel_taker || taker_decrement > Decimal::ZERO {
+            taker_order.cancel(CancelReason::SelfTradePrevention);
+        } else {
+            taker_order.cancel(CancelReason::Unfilled);
+        }
+        self.commit_book_changes(&pair.pair_symbol, &trades);
+        let taker_order_id = taker_order.order_id;
+        self.orders.insert(taker_order_id, taker_order);
+        let taker_fills: Vec<UserFillResponse> = fills
+            .iter()
+            .map(|fill| UserFillResponse::new(fill, Liquidity::Taker))
+            .collect();
+        self.publish_order_update(taker_order_id, &taker_fills, now);
+        Ok(fills)
+    }
 
-                        let trade_qty = std::cmp::min(
-                            taker_order.quantity - taker_order.filled_quantity,
-                            maker_order.quantity - maker_order.filled_quantity,
-                        );
+    /// Sends the current state of `order_id`, with any fills that just
+    /// happened to it, to its user's private stream.
+    /// Closed orders also go to the history sink, closed at `now`.
+    fn publish_order_update(&self, order_id: Uuid, fills: &[UserFillResponse], now: i64) {
+        let Some(order) = self.orders.get(&order_id) else {
+            return;
+        };
+        if let Some(history) = &self.history
+            && !order.is_open()
+        {
+            history.do_send(HistoryEvent::OrderClosed {
+                order: order.clone(),
+                closed_at: now,
+            });
+        }
+        let Some(hub) = &self.market_data else {
+            return;
+        };
+        hub.do_send(Publish {
+            channel: user_channel(&order.user_id),
+            data: serde_json::json!({
+                "type": "order",
+                "order": OrderResponse::from(order.clone()),
+                "fills": fills,
+            }),
+        });
+    }
 
-                        taker_order.filled_quantity += trade_qty;
-                        maker_order.filled_quantity += trade_qty;
+    /// Sends every balance changed since the last call to its user's private
+    /// stream and to the history sink, along with the new ledger entries.
+    /// History keys each change by the newest entry behind it and takes that
+    /// entry's time, so a replay writes the same rows again.
+    fn publish_balance_changes(&mut self) {
+        let changes = self.balance_manager.take_changes();
+        let ledger_entries = self.balance_manager.ledger_mut().take_new_entries();
+        if self.market_data.is_none() && self.history.is_none() {
+            return;
+        }
+        let mut newest_entries = HashMap::new();
+        for entry in &ledger_entries {
+            if entry.account != Account::External {
+                newest_entries.insert(
+                    (entry.user_id.as_str(), entry.token.as_str()),
+                    (entry.entry_id, entry.timestamp),
+                );
+            }
+        }
+        for (user_id, token) in changes {
+            let Some(user_balance) = self.balance_manager.get_user_balance(&user_id) else {
+                continue;
+            };
+            let available = user_balance.get_balance(&token);
+            let locked = user_balance.get_locked(&token);
+            if let Some(hub) = &self.market_data {
+                let balance = BalanceResponse {
+                    available: available.to_string(),
+                    locked: locked.to_string(),
+                    token: token.clone(),
+                };
+                hub.do_send(Publish {
+                    channel: user_channel(&user_id),
+                    data: serde_json::json!({ "type": "balance", "balance": balance }),
+                });
+            }
+            if let Some(history) = &self.history
+                && let Some(&(entry_id, timestamp)) =
+                    newest_entries.get(&(user_id.as_str(), token.as_str()))
+            {
+                history.do_send(HistoryEvent::BalanceChanged {
+                    entry_id,
+                    user_id,
+                    token,
+                    available,
+                    locked,
+                    timestamp,
+                });
+            }
+        }
+        if let Some(history) = &self.history
+            && !ledger_entries.is_empty()
+        {
+            history.do_send(HistoryEvent::LedgerEntries(ledger_entries));
+        }
+    }
 
-                        fills.push(Fill {
-                            trade_id: Uuid::new_v4(),
-                            price: maker_order.price,
-                            quantity: trade_qty,
-                            maker_order_id: maker_order.order_id,
-                            taker_order_id: taker_order.order_id,
-                            timestamp: chrono::Utc::now().timestamp_millis(),
-                        });
+    /// Closes the current book update of `market_pair`, advancing its
+    /// sequence if any level changed, and publishes the resulting depth diff,
+    /// `trades`, candles and ticker to stream subscribers. `trades` also go to
+    /// the history sink.
+    fn commit_book_changes(&mut self, market_pair: &str, trades: &[Trade]) {
+        let Some(market) = self.market_manager.get_market_mut(market_pair) else {
+            return;
+        };
+        let diff = market.orderbook.commit_changes();
+        let evicted = market.trades.take_evicted();
+        if let Some(spill) = &self.trade_spill
+            && !evicted.is_empty()
+        {
+            spill.do_send(SpillTrades {
+                market: market_pair.to_string(),
+                trades: evicted,
+            });
+        }
+        if let Some(history) = &self.history
+            && !trades.is_empty()
+        {
+            history.do_send(HistoryEvent::Trades(trades.to_vec()));
+        }
+        let Some(hub) = &self.market_data else {
+            return;
+        };
+        if diff.is_none() && trades.is_empty() {
+            return;
+        }
 
-                        if maker_order.quantity == maker_order.filled_quantity {
-                            orders_to_remove.push(maker_order.order_id);
-                        }
-                    }
+        let publish = |channel: String, data: serde_json::Value| {
+            hub.do_send(Publish { channel, data });
+        };
+        if let Some(diff) = diff {
+            publish(
+                format!("depth@{}", market_pair),
+                serde_json::json!(DepthDiffResponse::from(diff)),
+            );
+        }
+        for trade in trades {
+            publish(
+                format!("trades@{}", market_pair),
+                serde_json::json!(TradeResponse::from(trade.clone())),
+            );
+        }
+        if let Some(last_trade) = trades.last() {
+            for interval in CandleInterval::ALL {
+                if let Some(candle) = market.candles.candle_at(interval, last_trade.timestamp) {
+                    publish(
+                        format!("candles@{}:{}", market_pair, interval.as_str()),
+                        serde_json::json!(CandleResponse::from(candle)),
+                    );
                 }
             }
-            Side::Buy => {
-                for (price, orders_at_price) in self.orderbook.asks.iter_mut() {
-                    if taker_order.price < *price {
-                        break;
-                    } // Taker wants to buy for less than sellers are asking
-
-                    for maker_order in orders_at_price.iter_mut() {
-                        if taker_order.filled_quantity >= taker_order.quantity {
-                            break;
-                        }
-
-                        let trade_qty = std::cmp::min(
-                            taker_order.quantity - taker_order.filled_quantity,
-                            maker_order.quantity - maker_order.filled_quantity,
-                        );
-
-                        taker_order.filled_quantity += trade_qty;
-                        maker_order.filled_quantity += trade_qty;
-
-                        fills.push(Fill {
-                            trade_id: Uuid::new_v4(),
-                            price: maker_order.price,
-                            quantity: trade_qty,
-                            maker_order_id: maker_order.order_id,
-                            taker_order_id: taker_order.order_id,
-                            timestamp: chrono::Utc::now().timestamp_millis(),
-                        });
-
-                        if maker_order.quantity == maker_order.filled_quantity {
-                            orders_to_remove.push(maker_order.order_id);
-                        }
-                    }
+        }
+        let ticker = market.ticker(self.clock.now_millis());
+        publish(
+            format!("ticker@{}", market_pair),
+            serde_json::json!(TickerResponse::from(ticker)),
+        );
+    }
+
+    /// Releases stop orders whose stop price the last trade has reached. Each
+    /// triggered order can move the price again, so the stop book is
+    /// re-checked after every execution until nothing more fires.
+    fn run_stop_triggers(&mut self, market_pair: &str, now: i64) {
+        loop {
+            let Some(market) = self.market_manager.get_market_mut(market_pair) else {
+                return;
+            };
+            let Some(stop_order) = market.stop_book.pop_triggered(market.price) else {
+                return;
+            };
+            println!(
+                "Stop order {} triggered at {}",
+                stop_order.order_id, market.price
+            );
+
+            let order_id = stop_order.order_id;
+            if let Err(e) = self.execute_order(stop_order, now) {
+                println!("Dropping stop order {}: {}", order_id, e);
+                if let Some(order) = self.orders.get_mut(&order_id) {
+                    order.status = OrderStatus::Rejected;
                 }
+                self.publish_order_update(order_id, &[], now);
+            }
+        }
+    }
+
+    /// Pulls an open order from the orderbook or stop book, unlocks its funds
+    /// and marks it cancelled for `reason`.
+    fn cancel_open_order(
+        &mut self,
+        order_id: Uuid,
+        reason: CancelReason,
+        now: i64,
+    ) -> Result<(), String> {
+        let order = self
+            .orders
+            .get(&order_id)
+            .ok_or_else(|| "Order not found".to_string())?;
+        if !order.is_open() {
+            return Err("Order is no longer open".to_string());
+        }
+
+        // Untriggered stops live in the stop book rather than the orderbook
+        if let Some(stop_price) = order.stop_price
+            && let Some(market) = self.market_manager.get_market_mut(&order.market)
+            && let Some(stop_order) =
+                market
+                    .stop_book
+                    .remove_order(order.order_id, &order.side, stop_price)
+        {
+            self.orders.get_mut(&order_id).unwrap().cancel(reason);
+            self.release_order_lock(&stop_order, now);
+            self.publish_order_update(order_id, &[], now);
+            return Ok(());
+        }
+
+        // Clone to avoid overlapping mutable borrows of `self`.
+        let order = order.clone();
+        self.orders.get_mut(&order_id).unwrap().cancel(reason);
+
+        if let Some(market) = self.market_manager.get_market_mut(&order.market) {
+            market
+                .orderbook
+                .remove_order(order.order_id, &order.side, order.price);
+        }
+        self.commit_book_changes(&order.market, &[]);
+        self.release_order_lock(&order, now);
+        self.publish_order_update(order_id, &[], now);
+        Ok(())
+    }
+
+    /// Removes GTD orders whose expiry is at or before `now` and unlocks
+    /// their funds.
+    fn expire_orders(&mut self, now: i64) {
+        while let Some(&(expires_at, order_id)) = self.gtd_expiries.first() {
+            if expires_at > now {
+                break;
+            }
+            self.gtd_expiries.pop_first();
+
+            // Skip orders that were cancelled or filled in the meantime
+            let Some(order) = self.orders.get_mut(&order_id).filter(|o| o.is_open()) else {
+                continue;
+            };
+            order.status = OrderStatus::Expired;
+            let order = order.clone();
+
+            if let Some(market) = self.market_manager.get_market_mut(&order.market) {
+                market
+                    .orderbook
+                    .remove_order(order.order_id, &order.side, order.price);
+            }
+            self.commit_book_changes(&order.market, &[]);
+            self.release_order_lock(&order, now);
+            self.publish_order_update(order_id, &[], now);
+            println!("Expired GTD order {}", order.order_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{FixedClock, SequentialIds};
+    use crate::ledger::LedgerReason;
+    use crate::ticker::TICKER_WINDOW_MILLIS;
+    use std::fs;
+
+    fn limit(user_id: &str, side: Side, price: i64, quantity: i64) -> CreateMarketOrder {
+        CreateMarketOrder {
+            user_id: user_id.to_string(),
+            market: "TAN_KAN".to_string(),
+            side,
+            order_type: OrderType::Limit,
+            price: Some(Decimal::from(price)),
+            quantity: Decimal::from(quantity),
+            max_slippage_bps: None,
+            worst_price: None,
+            time_in_force: TimeInForce::Gtc,
+            expires_at: None,
+            post_only: false,
+            post_only_reprice: false,
+            stop_price: None,
+            display_quantity: None,
+            self_trade_prevention: SelfTradePrevention::CancelNewest,
+        }
+    }
+
+    fn deposit(user_id: &str, token: &str, amount: i64) -> Deposit {
+        Deposit {
+            user_id: user_id.to_string(),
+            token: token.to_string(),
+            amount: Decimal::from(amount),
+        }
+    }
+
+    fn seeded_engine(clock: i64) -> MatchingEngine {
+        MatchingEngine::with_sources(Box::new(FixedClock(clock)), Box::new(SequentialIds::new(7)))
+    }
+
+    fn balance(engine: &MatchingEngine, user_id: &str, token: &str) -> (Decimal, Decimal) {
+        engine
+            .balance_manager
+            .get_user_balance(user_id)
+            .map_or((Decimal::ZERO, Decimal::ZERO), |b| {
+                (b.get_balance(token), b.get_locked(token))
+            })
+    }
+
+    #[actix::test]
+    async fn journal_replays_to_the_same_state_hash() {
+        let dir = std::env::temp_dir().join(format!("cex-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let journal = dir.join("journal.log");
+
+        let mut engine = seeded_engine(1_000);
+        engine.open_journal(&journal).unwrap();
+        let engine = engine.start();
+        engine
+            .send(deposit("alice", "KAN", 1_000))
+            .await
+            .unwrap()
+            .unwrap();
+        engine
+            .send(deposit("bob", "TAN", 100))
+            .await
+            .unwrap()
+            .unwrap();
+        let resting = engine
+            .send(limit("bob", Side::Sell, 5, 10))
+            .await
+            .unwrap()
+            .unwrap();
+        engine
+            .send(limit("alice", Side::Buy, 6, 4))
+            .await
+            .unwrap()
+            .unwrap();
+        engine
+            .send(CancelOrder {
+                order_id: resting,
+                user_id: "bob".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let (sequence, live_hash) = engine.send(GetStateHash).await.unwrap().unwrap();
+        assert_eq!(sequence, 5);
+
+        for _ in 0..2 {
+            let mut replayed = seeded_engine(0);
+            assert_eq!(replayed.replay_journal(&journal).unwrap(), 5);
+            assert_eq!(replayed.state_hash().unwrap(), live_hash);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix::test]
+    async fn snapshots_drop_the_journal_segments_they_cover() {
+        let dir = std::env::temp_dir().join(format!("cex-test-{}", Uuid::new_v4()));
+        let snapshots = dir.join("snapshots");
+        fs::create_dir_all(&snapshots).unwrap();
+        let journal = dir.join("journal.log");
+
+        let mut engine = seeded_engine(1_000);
+        engine.open_journal(&journal).unwrap();
+        engine.enable_snapshots(snapshots.clone());
+        let engine = engine.start();
+        for _ in 0..5 {
+            engine
+                .send(deposit("alice", "KAN", 10))
+                .await
+                .unwrap()
+                .unwrap();
+            engine.send(TakeSnapshot).await.unwrap().unwrap();
+        }
+        engine
+            .send(deposit("alice", "KAN", 10))
+            .await
+            .unwrap()
+            .unwrap();
+        let (sequence, live_hash) = engine.send(GetStateHash).await.unwrap().unwrap();
+        assert_eq!(sequence, 6);
+
+        // Snapshots at 3, 4 and 5 are kept, so entries 1 to 3 are gone
+        let entries = Journal::read(&journal).unwrap();
+        let sequences: Vec<u64> = entries.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, [4, 5, 6]);
+
+        let mut restored = seeded_engine(0);
+        assert_eq!(restored.restore_snapshot(&snapshots).unwrap(), Some(5));
+        assert_eq!(restored.replay_journal(&journal).unwrap(), 1);
+        assert_eq!(restored.state_hash().unwrap(), live_hash);
+
+        assert!(seeded_engine(0).replay_journal(&journal).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn limit_buy_settles_at_maker_price_and_refunds_the_difference() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine
+            .create_order(limit("alice", Side::Buy, 6, 4), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        // Alice reserved 24 KAN but the trade printed at 5
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::from(80), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "TAN"),
+            (Decimal::from(4), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "bob", "KAN"),
+            (Decimal::from(20), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "bob", "TAN"),
+            (Decimal::ZERO, Decimal::from(6))
+        );
+    }
+
+    #[test]
+    fn an_order_that_cannot_settle_is_rejected_before_matching() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        let resting = engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+        // Break the invariant that a resting order is fully reserved
+        engine
+            .balance_manager
+            .unlock_funds("bob", "TAN", Decimal::from(8), resting, 1_000)
+            .unwrap();
+
+        let taker = engine.create_order(limit("alice", Side::Buy, 5, 4), Uuid::new_v4(), 1_000);
+        assert!(taker.is_err());
+        assert_eq!(
+            engine.orders[&resting].remaining_quantity(),
+            Decimal::from(10)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::from(100), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "bob", "TAN"),
+            (Decimal::from(8), Decimal::from(2))
+        );
+        assert!(!engine.user_orders.contains_key("alice"));
+    }
+
+    #[test]
+    fn fok_is_rejected_when_own_orders_would_shrink_it() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "TAN", 10), 1_000).unwrap();
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        engine
+            .create_order(limit("alice", Side::Sell, 5, 4), Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        let mut fok = limit("alice", Side::Buy, 5, 10);
+        fok.time_in_force = TimeInForce::Fok;
+        fok.self_trade_prevention = SelfTradePrevention::DecrementAndCancel;
+        assert!(engine.create_order(fok, Uuid::new_v4(), 1_000).is_err());
+        assert_eq!(
+            balance(&engine, "bob", "TAN"),
+            (Decimal::ZERO, Decimal::from(10))
+        );
+    }
+
+    #[test]
+    fn ioc_cancels_its_remainder_and_gtd_expires() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        let mut gtd = limit("bob", Side::Sell, 5, 6);
+        gtd.time_in_force = TimeInForce::Gtd;
+        gtd.expires_at = Some(2_000);
+        let gtd = engine.create_order(gtd, Uuid::new_v4(), 1_000).unwrap();
+
+        let mut ioc = limit("alice", Side::Buy, 5, 8);
+        ioc.time_in_force = TimeInForce::Ioc;
+        let ioc = engine.create_order(ioc, Uuid::new_v4(), 1_000).unwrap();
+        assert_eq!(engine.orders[&ioc].filled_quantity, Decimal::from(6));
+        assert_eq!(engine.orders[&ioc].status, OrderStatus::Cancelled);
+        assert_eq!(
+            engine.orders[&ioc].cancel_reason,
+            Some(CancelReason::Unfilled)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::from(70), Decimal::ZERO)
+        );
+
+        let mut gtd_rest = limit("bob", Side::Sell, 6, 4);
+        gtd_rest.time_in_force = TimeInForce::Gtd;
+        gtd_rest.expires_at = Some(2_000);
+        let gtd_rest = engine
+            .create_order(gtd_rest, Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine.expire_orders(1_999);
+        assert!(engine.orders[&gtd_rest].is_open());
+        engine.expire_orders(2_000);
+        assert_eq!(engine.orders[&gtd_rest].status, OrderStatus::Expired);
+        assert_eq!(engine.orders[&gtd].status, OrderStatus::Filled);
+        assert_eq!(
+            balance(&engine, "bob", "TAN"),
+            (Decimal::from(4), Decimal::ZERO)
+        );
+    }
+
+    fn amend(order_id: Uuid, user_id: &str, quantity: i64) -> AmendOrder {
+        AmendOrder {
+            order_id,
+            user_id: user_id.to_string(),
+            price: None,
+            quantity: Some(Decimal::from(quantity)),
+        }
+    }
+
+    #[test]
+    fn shrinking_keeps_queue_priority_and_growing_loses_it() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 20), 1_000).unwrap();
+        engine.deposit(deposit("carol", "TAN", 20), 1_000).unwrap();
+        let bob = engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+        let carol = engine
+            .create_order(limit("carol", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        engine.amend_order(amend(bob, "bob", 4), 1_000).unwrap();
+        assert_eq!(
+            balance(&engine, "bob", "TAN"),
+            (Decimal::from(16), Decimal::from(4))
+        );
+        engine
+            .create_order(limit("alice", Side::Buy, 5, 4), Uuid::new_v4(), 1_000)
+            .unwrap();
+        assert_eq!(engine.orders[&bob].status, OrderStatus::Filled);
+
+        let later = engine
+            .create_order(limit("bob", Side::Sell, 5, 2), Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine
+            .amend_order(amend(carol, "carol", 12), 1_000)
+            .unwrap();
+        assert_eq!(
+            balance(&engine, "carol", "TAN"),
+            (Decimal::from(8), Decimal::from(12))
+        );
+        engine
+            .create_order(limit("alice", Side::Buy, 5, 1), Uuid::new_v4(), 1_000)
+            .unwrap();
+        // Carol's grown order went to the back, behind the later one
+        assert_eq!(engine.orders[&carol].filled_quantity, Decimal::ZERO);
+        assert_eq!(engine.orders[&later].filled_quantity, Decimal::ONE);
+    }
+
+    #[actix::test]
+    async fn reading_the_ticker_leaves_the_state_hash_unchanged() {
+        // The clock reads a day after the trade, so the ticker window is empty
+        let mut engine = seeded_engine(1_000 + TICKER_WINDOW_MILLIS + 1);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine
+            .create_order(limit("alice", Side::Buy, 5, 4), Uuid::new_v4(), 1_000)
+            .unwrap();
+        let hash = engine.state_hash().unwrap();
+
+        let engine = engine.start();
+        let ticker = engine
+            .send(GetTicker {
+                market_pair: "TAN_KAN".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ticker.trade_count, 0);
+        engine.send(GetTickers).await.unwrap();
+        let (_, after) = engine.send(GetStateHash).await.unwrap().unwrap();
+        assert_eq!(after, hash);
+    }
+
+    #[test]
+    fn stop_market_sell_triggers_on_the_last_trade_price() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        engine.deposit(deposit("dave", "TAN", 10), 1_000).unwrap();
+        engine
+            .create_order(limit("alice", Side::Buy, 4, 3), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        let mut stop = limit("dave", Side::Sell, 0, 2);
+        stop.order_type = OrderType::StopMarket;
+        stop.price = None;
+        stop.stop_price = Some(Decimal::from(5));
+        // The last price is 5, so a sell stop at 5 would fire at once
+        assert!(
+            engine
+                .create_order(stop.clone(), Uuid::new_v4(), 1_000)
+                .is_err()
+        );
+        stop.stop_price = Some(Decimal::from(4));
+        let stop_id = engine.create_order(stop, Uuid::new_v4(), 1_000).unwrap();
+        assert_eq!(engine.orders[&stop_id].status, OrderStatus::New);
+        assert_eq!(
+            balance(&engine, "dave", "TAN"),
+            (Decimal::from(8), Decimal::from(2))
+        );
+
+        // Bob's sale prints at 4 and releases the stop into Alice's bid
+        engine
+            .create_order(limit("bob", Side::Sell, 4, 1), Uuid::new_v4(), 1_000)
+            .unwrap();
+        assert_eq!(engine.orders[&stop_id].status, OrderStatus::Filled);
+        assert_eq!(
+            balance(&engine, "dave", "KAN"),
+            (Decimal::from(8), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "TAN"),
+            (Decimal::from(3), Decimal::ZERO)
+        );
+    }
+
+    #[test]
+    fn post_only_rejects_or_reprices_a_crossing_order() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        engine
+            .create_order(limit("bob", Side::Sell, 5, 10), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        let mut post_only = limit("alice", Side::Buy, 6, 4);
+        post_only.post_only = true;
+        assert!(
+            engine
+                .create_order(post_only.clone(), Uuid::new_v4(), 1_000)
+                .is_err()
+        );
+
+        // Re-priced one tick inside the ask, so it rests instead of trading
+        post_only.post_only_reprice = true;
+        let order_id = engine
+            .create_order(post_only, Uuid::new_v4(), 1_000)
+            .unwrap();
+        let order = &engine.orders[&order_id];
+        assert_eq!(order.price, Decimal::new(499, 2));
+        assert_eq!(order.filled_quantity, Decimal::ZERO);
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::new(8004, 2), Decimal::new(1996, 2))
+        );
+    }
+
+    #[test]
+    fn mass_cancel_filters_by_market_and_side() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("alice", "TAN", 10), 1_000).unwrap();
+        engine.deposit(deposit("alice", "PRA", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "KAN", 100), 1_000).unwrap();
+        let bid = engine
+            .create_order(limit("alice", Side::Buy, 4, 5), Uuid::new_v4(), 1_000)
+            .unwrap();
+        let ask = engine
+            .create_order(limit("alice", Side::Sell, 6, 5), Uuid::new_v4(), 1_000)
+            .unwrap();
+        let mut other_market = limit("alice", Side::Buy, 2, 5);
+        other_market.market = "ADI_PRA".to_string();
+        let other = engine
+            .create_order(other_market, Uuid::new_v4(), 1_000)
+            .unwrap();
+        let bobs = engine
+            .create_order(limit("bob", Side::Buy, 4, 5), Uuid::new_v4(), 1_000)
+            .unwrap();
+
+        let cancelled = engine
+            .cancel_all_orders(
+                CancelAllOrders {
+                    user_id: "alice".to_string(),
+                    market: Some("TAN_KAN".to_string()),
+                    side: Some(Side::Buy),
+                },
+                1_000,
+            )
+            .unwrap();
+        assert_eq!(cancelled, [bid]);
+        assert_eq!(
+            engine.orders[&bid].cancel_reason,
+            Some(CancelReason::MassCancel)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::from(100), Decimal::ZERO)
+        );
+
+        let cancelled = engine
+            .cancel_all_orders(
+                CancelAllOrders {
+                    user_id: "alice".to_string(),
+                    market: None,
+                    side: None,
+                },
+                1_000,
+            )
+            .unwrap();
+        assert_eq!(cancelled.len(), 2);
+        assert!(cancelled.contains(&ask) && cancelled.contains(&other));
+        assert!(engine.orders[&bobs].is_open());
+        assert_eq!(
+            balance(&engine, "alice", "TAN"),
+            (Decimal::from(10), Decimal::ZERO)
+        );
+    }
+
+    #[actix::test]
+    async fn user_orders_page_newest_first() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        let placed: Vec<Uuid> = (1..=5)
+            .map(|price| {
+                engine
+                    .create_order(limit("alice", Side::Buy, price, 1), Uuid::new_v4(), 1_000)
+                    .unwrap()
+            })
+            .collect();
+        engine
+            .cancel_order(
+                CancelOrder {
+                    order_id: placed[1],
+                    user_id: "alice".to_string(),
+                },
+                1_000,
+            )
+            .unwrap();
+
+        let engine = engine.start();
+        let page = |status, cursor, limit| GetUserOrders {
+            user_id: "alice".to_string(),
+            status,
+            market: None,
+            cursor,
+            limit,
+        };
+        let ids = |page: &OrderPage| page.orders.iter().map(|o| o.order_id).collect::<Vec<_>>();
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let orders = engine.send(page(None, cursor, 2)).await.unwrap().unwrap();
+            assert!(orders.orders.len() <= 2);
+            seen.extend(ids(&orders));
+            cursor = orders.next_cursor;
+            if cursor.is_none() {
+                break;
             }
         }
+        assert_eq!(seen, placed.iter().rev().copied().collect::<Vec<_>>());
+
+        let open = engine
+            .send(page(Some(OrderStatusFilter::Open), None, 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&open), [placed[4], placed[3], placed[2], placed[0]]);
+        let cancelled = engine
+            .send(page(Some(OrderStatusFilter::Cancelled), None, 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&cancelled), [placed[1]]);
+        assert!(engine.send(page(None, None, 0)).await.unwrap().is_err());
+    }
+
+    #[test]
+    fn order_status_follows_fills_and_cancels() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        let maker = engine
+            .create_order(limit("bob", Side::Sell, 5, 6), Uuid::new_v4(), 1_000)
+            .unwrap();
+        assert_eq!(engine.orders[&maker].status, OrderStatus::New);
+
+        let taker = engine
+            .create_order(limit("alice", Side::Buy, 5, 2), Uuid::new_v4(), 1_000)
+            .unwrap();
+        assert_eq!(engine.orders[&taker].status, OrderStatus::Filled);
+        assert_eq!(engine.orders[&maker].status, OrderStatus::PartiallyFilled);
+
+        // Partially filled, then its rest cancelled by the user
+        engine
+            .cancel_order(
+                CancelOrder {
+                    order_id: maker,
+                    user_id: "bob".to_string(),
+                },
+                1_000,
+            )
+            .unwrap();
+        let maker = &engine.orders[&maker];
+        assert_eq!(maker.status, OrderStatus::Cancelled);
+        assert_eq!(maker.cancel_reason, Some(CancelReason::UserRequested));
+        assert_eq!(maker.filled_quantity, Decimal::from(2));
+
+        // Nothing left to take: an IOC is cancelled as unfilled
+        let mut ioc = limit("alice", Side::Buy, 5, 2);
+        ioc.time_in_force = TimeInForce::Ioc;
+        let ioc = engine.create_order(ioc, Uuid::new_v4(), 1_000).unwrap();
+        assert_eq!(engine.orders[&ioc].status, OrderStatus::Cancelled);
+        assert_eq!(
+            engine.orders[&ioc].cancel_reason,
+            Some(CancelReason::Unfilled)
+        );
+    }
+
+    #[test]
+    fn market_buy_stops_at_its_slippage_band() {
+        let mut engine = seeded_engine(1_000);
+        engine
+            .deposit(deposit("alice", "KAN", 1_000), 1_000)
+            .unwrap();
+        engine.deposit(deposit("bob", "TAN", 10), 1_000).unwrap();
+        for (price, quantity) in [(100, 2), (101, 3), (103, 5)] {
+            engine
+                .create_order(
+                    limit("bob", Side::Sell, price, quantity),
+                    Uuid::new_v4(),
+                    1_000,
+                )
+                .unwrap();
+        }
+
+        // 100 bps above the best ask of 100 reaches 101 but not 103
+        let mut market = limit("alice", Side::Buy, 0, 10);
+        market.order_type = OrderType::Market;
+        market.price = None;
+        market.max_slippage_bps = Some(100);
+        let order_id = engine.create_order(market, Uuid::new_v4(), 1_000).unwrap();
+
+        let order = &engine.orders[&order_id];
+        assert_eq!(order.filled_quantity, Decimal::from(5));
+        assert_eq!(order.cancel_reason, Some(CancelReason::Unfilled));
+        assert_eq!(
+            balance(&engine, "alice", "KAN"),
+            (Decimal::from(497), Decimal::ZERO)
+        );
+        assert_eq!(
+            balance(&engine, "alice", "TAN"),
+            (Decimal::from(5), Decimal::ZERO)
+        );
+
+        // A worst price tighter than the band wins
+        assert_eq!(
+            market_price_limit(
+                &Side::Sell,
+                Decimal::from(100),
+                Some(100),
+                Some(Decimal::new(995, 1))
+            ),
+            Some(Decimal::new(995, 1))
+        );
+    }
+
+    #[actix::test]
+    async fn ledger_pages_newest_first_by_entry_id() {
+        let mut engine = seeded_engine(1_000);
+        engine.deposit(deposit("alice", "KAN", 100), 1_000).unwrap();
+        let order_id = engine
+            .create_order(limit("alice", Side::Buy, 5, 4), Uuid::new_v4(), 1_000)
+            .unwrap();
+        engine
+            .cancel_order(
+                CancelOrder {
+                    order_id,
+                    user_id: "alice".to_string(),
+                },
+                1_000,
+            )
+            .unwrap();
+        engine.deposit(deposit("alice", "TAN", 5), 1_000).unwrap();
 
-        // Clean up fully filled orders without overlapping borrows
-        for id in orders_to_remove {
-            if let Some(order) = self.orders.remove(&id) {
-                self.remove_order_from_book(id, order.side, order.price);
+        let engine = engine.start();
+        let page = |token: Option<&str>, cursor, limit| GetLedger {
+            user_id: "alice".to_string(),
+            token: token.map(str::to_string),
+            cursor,
+            limit,
+        };
+
+        let mut entries = Vec::new();
+        let mut cursor = None;
+        loop {
+            let ledger = engine.send(page(None, cursor, 3)).await.unwrap().unwrap();
+            assert!(ledger.entries.len() <= 3);
+            entries.extend(ledger.entries);
+            cursor = ledger.next_cursor;
+            if cursor.is_none() {
+                break;
             }
         }
+        // Deposit, lock, unlock and deposit, two entries each
+        let reasons: Vec<LedgerReason> = entries.iter().rev().map(|e| e.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                LedgerReason::Deposit,
+                LedgerReason::Deposit,
+                LedgerReason::Lock,
+                LedgerReason::Lock,
+                LedgerReason::Unlock,
+                LedgerReason::Unlock,
+                LedgerReason::Deposit,
+                LedgerReason::Deposit,
+            ]
+        );
+        assert!(entries.windows(2).all(|w| w[0].entry_id > w[1].entry_id));
+        assert!(
+            entries
+                .iter()
+                .filter(|e| e.reason == LedgerReason::Lock)
+                .all(|e| e.reference_id == Some(order_id))
+        );
+
+        let tan = engine
+            .send(page(Some("TAN"), None, 10))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tan.entries.len(), 2);
+        assert!(tan.next_cursor.is_none());
+        assert!(engine.send(page(None, None, 0)).await.unwrap().is_err());
+    }
 
-        fills
+    #[test]
+    fn out_of_range_amounts_are_rejected() {
+        let mut engine = seeded_engine(1_000);
+        let mut huge = limit("alice", Side::Buy, 1, 2);
+        huge.price = Some(Decimal::MAX);
+        assert!(engine.create_order(huge, Uuid::new_v4(), 1_000).is_err());
+        let mut huge = deposit("alice", "KAN", 1);
+        huge.amount = Decimal::MAX;
+        assert!(engine.deposit(huge, 1_000).is_err());
+        assert!(engine.balance_manager.get_user_balance("alice").is_none());
+    }
+
+    #[actix::test]
+    async fn errors_say_what_kind_of_failure_they_are() {
+        let engine = seeded_engine(1_000).start();
+        let depth = |market_pair: &str, limit| GetMarketDepth {
+            market_pair: market_pair.to_string(),
+            limit,
+            step: None,
+        };
+        assert!(matches!(
+            engine.send(depth("NOPE_KAN", None)).await.unwrap(),
+            Err(EngineError::NotFound(_))
+        ));
+        assert!(matches!(
+            engine.send(depth("TAN_KAN", Some(0))).await.unwrap(),
+            Err(EngineError::Rejected(_))
+        ));
+        assert!(matches!(
+            engine.send(TakeSnapshot).await.unwrap(),
+            Err(EngineError::Unavailable(_))
+        ));
+        let mut huge = deposit("alice", "KAN", 1);
+        huge.amount = Decimal::MAX;
+        assert!(matches!(
+            engine.send(huge).await.unwrap(),
+            Err(EngineError::Rejected(_))
+        ));
     }
 }
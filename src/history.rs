@@ -0,0 +1,29 @@
+use crate::input::Order;
+use crate::ledger::LedgerEntry;
+use crate::trades::Trade;
+use actix::Message;
+use rust_decimal::Decimal;
+
+/// Settled facts the engine hands to a history backend, such as the SQLite
+/// writer. Only what can no longer change is sent: orders once they are
+/// closed, trades, ledger entries and each balance after it changed.
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub enum HistoryEvent {
+    /// The order is filled, cancelled, rejected or expired.
+    OrderClosed {
+        order: Order,
+        closed_at: i64,
+    },
+    Trades(Vec<Trade>),
+    LedgerEntries(Vec<LedgerEntry>),
+    /// `entry_id` is the newest ledger entry behind the change.
+    BalanceChanged {
+        entry_id: u64,
+        user_id: String,
+        token: String,
+        available: Decimal,
+        locked: Decimal,
+        timestamp: i64,
+    },
+}
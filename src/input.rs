@@ -1,4 +1,3 @@
-use actix_web::web::Json;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -12,15 +11,88 @@ pub struct CreateOrderInput {
     pub side: Side,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
     pub order_id: Uuid,
     pub user_id: String,
+    pub market: String,
     pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    /// Maker-only: the order is never allowed to take liquidity.
+    pub post_only: bool,
+    /// Last trade price that releases a stop order into matching.
+    pub stop_price: Option<Decimal>,
+    /// Applied when this order, as taker, meets a resting order of the same
+    /// user.
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Iceberg slice size. Only this much is shown on the book at a time; the
+    /// rest is a hidden reserve.
+    pub display_quantity: Option<Decimal>,
+    /// Unix millis after which a GTD order is removed from the book.
+    pub expires_at: Option<i64>,
+    /// Limit price. For market orders this is the worst price the order may
+    /// trade at, or zero when no slippage bound was given.
     pub price: Decimal,
     pub quantity: Decimal,
     pub filled_quantity: Decimal,
     pub timestamp: i64,
+    pub status: OrderStatus,
+    /// Set once the order is `Cancelled`.
+    pub cancel_reason: Option<CancelReason>,
+}
+
+impl Order {
+    pub fn remaining_quantity(&self) -> Decimal {
+        self.quantity - self.filled_quantity
+    }
+
+    /// Quantity shown on the book and available to the next taker at this
+    /// queue position. For icebergs this is what is left of the current slice.
+    pub fn visible_quantity(&self) -> Decimal {
+        match self.display_quantity {
+            Some(display) => {
+                (display - self.filled_quantity % display).min(self.remaining_quantity())
+            }
+            None => self.remaining_quantity(),
+        }
+    }
+
+    /// Worst price this order may trade at; `None` for an unbounded market
+    /// order.
+    pub fn limit_price(&self) -> Option<Decimal> {
+        if self.order_type.is_market() {
+            (self.price > Decimal::ZERO).then_some(self.price)
+        } else {
+            Some(self.price)
+        }
+    }
+
+    /// Whether an unfilled remainder is placed on the book rather than
+    /// cancelled.
+    pub fn rests_on_book(&self) -> bool {
+        !self.order_type.is_market()
+            && matches!(self.time_in_force, TimeInForce::Gtc | TimeInForce::Gtd)
+    }
+
+    /// Resting on the book or waiting in the stop book.
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, OrderStatus::New | OrderStatus::PartiallyFilled)
+    }
+
+    /// Status of an order that is still open after trading.
+    pub fn open_status(&self) -> OrderStatus {
+        if self.filled_quantity > Decimal::ZERO {
+            OrderStatus::PartiallyFilled
+        } else {
+            OrderStatus::New
+        }
+    }
+
+    pub fn cancel(&mut self, reason: CancelReason) {
+        self.status = OrderStatus::Cancelled;
+        self.cancel_reason = Some(reason);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,6 +111,120 @@ pub enum Side {
     Sell,
 }
 
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OrderType {
+    #[default]
+    Limit,
+    /// Sweeps the book until filled or out of liquidity; the remainder is
+    /// cancelled instead of resting.
+    Market,
+    /// Held in the market's stop book until the last trade price reaches
+    /// `stop_price`, then executed as a market order.
+    StopMarket,
+    /// As `StopMarket`, but executed as a limit order at `price`.
+    StopLimit,
+}
+
+impl OrderType {
+    /// Market-style execution: no resting, sweeps up to an optional bound.
+    pub fn is_market(self) -> bool {
+        matches!(self, OrderType::Market | OrderType::StopMarket)
+    }
+
+    pub fn is_stop(self) -> bool {
+        matches!(self, OrderType::StopMarket | OrderType::StopLimit)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum TimeInForce {
+    /// Good till cancelled: the remainder rests on the book.
+    #[default]
+    Gtc,
+    /// Immediate or cancel: the remainder is cancelled.
+    Ioc,
+    /// Fill or kill: rejected unless the whole quantity can fill at once.
+    Fok,
+    /// Good till date: rests until `expires_at`.
+    Gtd,
+}
+
+/// What happens when a taker would trade against its own user's resting
+/// order. The taker's setting decides.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SelfTradePrevention {
+    /// Self trades are allowed.
+    #[default]
+    None,
+    /// Cancel the taker's remainder and stop matching.
+    CancelNewest,
+    /// Cancel the resting order and keep matching.
+    CancelOldest,
+    /// Cancel the resting order and the taker's remainder.
+    CancelBoth,
+    /// Reduce both orders by the smaller remaining quantity without trading;
+    /// whichever reaches zero is cancelled.
+    DecrementAndCancel,
+}
+
+/// Where an order is in its lifecycle. Orders in a final state are kept as
+/// history.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OrderStatus {
+    /// Open with nothing filled, including untriggered stops.
+    #[default]
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    /// Accepted, then refused when it came to execute, e.g. a triggered stop
+    /// the user could no longer fund.
+    Rejected,
+    /// A GTD order that reached `expires_at`.
+    Expired,
+}
+
+/// Why an order was cancelled.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CancelReason {
+    /// Cancelled by its user.
+    UserRequested,
+    /// Swept up by a cancel-all request.
+    MassCancel,
+    /// Pulled by the self-trade prevention mode of an incoming order.
+    SelfTradePrevention,
+    /// The remainder of a market, IOC or FOK order, which never rests.
+    Unfilled,
+}
+
+/// Coarse status groups accepted when listing a user's orders.
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatusFilter {
+    /// `New` or `PartiallyFilled`.
+    Open,
+    Filled,
+    /// Closed without filling completely: `Cancelled`, `Rejected` or
+    /// `Expired`.
+    Cancelled,
+}
+
+impl OrderStatusFilter {
+    pub fn matches(self, status: OrderStatus) -> bool {
+        match self {
+            OrderStatusFilter::Open => {
+                matches!(status, OrderStatus::New | OrderStatus::PartiallyFilled)
+            }
+            OrderStatusFilter::Filled => status == OrderStatus::Filled,
+            OrderStatusFilter::Cancelled => matches!(
+                status,
+                OrderStatus::Cancelled | OrderStatus::Rejected | OrderStatus::Expired
+            ),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 
 pub struct DeleteOrderInput {
@@ -50,5 +236,6 @@ pub struct DeleteOrderInput {
 pub struct Depth {
     pub bid: Vec<[u32; 2]>,
     pub ask: Vec<[u32; 2]>,
-    pub lastUpdatedId: String,
+    #[serde(rename = "lastUpdatedId")]
+    pub last_updated_id: String,
 }
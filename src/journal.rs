@@ -0,0 +1,310 @@
+use crate::engine::{
+    AmendOrder, CancelAllOrders, CancelOrder, CreateMarketOrder, Deposit, Withdraw,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Entries written before the journal is fsynced even if the sync timer has
+/// not fired yet.
+pub const JOURNAL_SYNC_BATCH: usize = 64;
+
+/// Longest an entry waits for its fsync.
+pub const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A state-changing engine command, as written to the journal.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum Command {
+    /// Carries the ID the order was given so it survives a replay.
+    CreateOrder {
+        order_id: Uuid,
+        order: CreateMarketOrder,
+    },
+    CancelOrder(CancelOrder),
+    CancelAllOrders(CancelAllOrders),
+    AmendOrder(AmendOrder),
+    Deposit(Deposit),
+    /// Carries the ID the withdrawal was given so it survives a replay.
+    Withdraw {
+        withdrawal_id: Uuid,
+        withdrawal: Withdraw,
+    },
+    /// Expiry of resting GTD orders, run by the engine's timer.
+    ExpireOrders,
+}
+
+/// One journal line. Commands are replayed with `timestamp` as the current
+/// time so time-dependent checks decide the same way again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    pub timestamp: i64,
+    pub command: Command,
+}
+
+/// Append-only command log. Each line is `<crc32 hex>\t<entry json>`; the
+/// checksum covers the JSON. Lines reach the OS as soon as they are written,
+/// so a process crash loses nothing, while fsyncs are batched and a power
+/// loss may drop up to `JOURNAL_SYNC_INTERVAL` of commands.
+///
+/// New entries go to the file at `path`. `rotate` seals it as
+/// `<path>.<first sequence>` so segments a snapshot covers can be deleted;
+/// reading goes through the sealed segments in order, then `path`.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Sequence of the first entry in the file at `path`, or of the next
+    /// one while it is empty.
+    segment_start: u64,
+    next_sequence: u64,
+    unsynced: usize,
+}
+
+impl Journal {
+    /// Opens or creates the journal at `path` and reads back its entries.
+    /// Reading stops at the first line that is torn or fails its checksum,
+    /// and the file is cut there so new entries follow the last good one.
+    /// Sealed segments were fsynced when sealed, so a bad line there is an
+    /// error instead.
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<JournalEntry>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut entries = read_sealed_segments(path)?;
+        let (tail, valid_len) = read_entries(&file)?;
+        if valid_len < file.metadata()?.len() {
+            println!(
+                "Journal {} is corrupt after {} entries, truncating",
+                path.display(),
+                tail.len()
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        let tail_start = tail.first().map(|e| e.sequence);
+        entries.extend(tail);
+
+        let next_sequence = entries.last().map_or(1, |e: &JournalEntry| e.sequence + 1);
+        let segment_start = tail_start.unwrap_or(next_sequence);
+        let journal = Self {
+            path: path.to_path_buf(),
+            file,
+            segment_start,
+            next_sequence,
+            unsynced: 0,
+        };
+        Ok((journal, entries))
+    }
+
+    /// Appends `command` and returns the sequence it was given. The entry is
+    /// handed to the OS before this returns; see `sync` for durability.
+    pub fn append(&mut self, timestamp: i64, command: &Command) -> io::Result<u64> {
+        #[derive(Serialize)]
+        struct EntryRef<'a> {
+            sequence: u64,
+            timestamp: i64,
+            command: &'a Command,
+        }
+
+        let sequence = self.next_sequence;
+        let json = serde_json::to_string(&EntryRef {
+            sequence,
+            timestamp,
+            command,
+        })?;
+        let line = format!("{:08x}\t{}\n", crc32fast::hash(json.as_bytes()), json);
+        self.file.write_all(line.as_bytes())?;
+        self.next_sequence += 1;
+
+        self.unsynced += 1;
+        if self.unsynced >= JOURNAL_SYNC_BATCH {
+            self.sync()?;
+        }
+        Ok(sequence)
+    }
+
+    /// Reads the entries of the journal at `path`, sealed segments included,
+    /// without changing it. Stops at the first torn or corrupt line of the
+    /// open segment.
+    pub fn read(path: &Path) -> io::Result<Vec<JournalEntry>> {
+        let mut entries = read_sealed_segments(path)?;
+        let (tail, _) = read_entries(&File::open(path)?)?;
+        entries.extend(tail);
+        Ok(entries)
+    }
+
+    /// Makes sure new entries are numbered after `sequence`, for a journal
+    /// that is behind the snapshot state was restored from.
+    pub fn continue_after(&mut self, sequence: u64) {
+        let segment_empty = self.segment_start == self.next_sequence;
+        self.next_sequence = self.next_sequence.max(sequence + 1);
+        if segment_empty {
+            self.segment_start = self.next_sequence;
+        }
+    }
+
+    /// Seals the open segment and starts a new one, so everything up to now
+    /// can later be dropped with `remove_segments_through`. Does nothing
+    /// while the open segment is empty.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        if self.next_sequence == self.segment_start {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+        self.unsynced = 0;
+
+        let sealed = segment_path(&self.path, self.segment_start);
+        fs::rename(&self.path, &sealed)?;
+        let file = match OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                // Keep appending to the old segment under its old name
+                let _ = fs::rename(&sealed, &self.path);
+                return Err(e);
+            }
+        };
+        sync_dir(&self.path)?;
+        self.file = file;
+        self.segment_start = self.next_sequence;
+        Ok(())
+    }
+
+    /// Deletes the sealed segments holding only entries up to `sequence`,
+    /// which a snapshot at `sequence` or later makes redundant.
+    pub fn remove_segments_through(&mut self, sequence: u64) -> io::Result<()> {
+        let segments = sealed_segments(&self.path)?;
+        // A segment ends just before the next one starts
+        let next_starts: Vec<u64> = segments
+            .iter()
+            .skip(1)
+            .map(|(start, _)| *start)
+            .chain([self.segment_start])
+            .collect();
+        let mut removed = false;
+        for ((_, segment), next_start) in segments.into_iter().zip(next_starts) {
+            if next_start > sequence + 1 {
+                break;
+            }
+            fs::remove_file(segment)?;
+            removed = true;
+        }
+        if removed {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Fsyncs everything appended so far.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(path: &Path, first_sequence: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{:020}", first_sequence));
+    PathBuf::from(name)
+}
+
+/// The directory holding the journal at `path` and its segments.
+fn journal_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(journal_dir(path))?.sync_all()
+}
+
+/// The sealed segments of the journal at `path` with the sequence each
+/// starts at, oldest first.
+fn sealed_segments(path: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}.", prefix);
+    let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(journal_dir(path))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter_map(|segment| {
+            let name = segment.file_name()?.to_str()?;
+            let start = name.strip_prefix(&prefix)?;
+            if start.len() != 20 || !start.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            Some((start.parse().ok()?, segment))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+fn read_sealed_segments(path: &Path) -> io::Result<Vec<JournalEntry>> {
+    let mut entries = Vec::new();
+    for (_, segment) in sealed_segments(path)? {
+        let file = File::open(&segment)?;
+        let (segment_entries, valid_len) = read_entries(&file)?;
+        if valid_len < file.metadata()?.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Journal segment {} is corrupt after {} entries",
+                    segment.display(),
+                    segment_entries.len()
+                ),
+            ));
+        }
+        entries.extend(segment_entries);
+    }
+    Ok(entries)
+}
+
+/// Entries up to the first bad line, and the length in bytes they cover.
+fn read_entries(file: &File) -> io::Result<(Vec<JournalEntry>, u64)> {
+    let mut entries = Vec::new();
+    let mut valid_len = 0u64;
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line)?;
+        if read == 0 {
+            break;
+        }
+        let Some(entry) = decode_line(&line) else {
+            break;
+        };
+        entries.push(entry);
+        valid_len += read as u64;
+    }
+    Ok((entries, valid_len))
+}
+
+fn decode_line(line: &[u8]) -> Option<JournalEntry> {
+    let line = std::str::from_utf8(line).ok()?.strip_suffix('\n')?;
+    let (checksum, json) = line.split_once('\t')?;
+    let checksum = u32::from_str_radix(checksum, 16).ok()?;
+    if crc32fast::hash(json.as_bytes()) != checksum {
+        return None;
+    }
+    serde_json::from_str(json).ok()
+}
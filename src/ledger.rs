@@ -0,0 +1,157 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Ledger entries kept in memory per user for `GET /users/{id}/ledger`.
+/// The full history goes to the history sink.
+pub const LEDGER_ENTRIES_KEPT: usize = 1_000;
+
+/// The user whose external account receives fees.
+pub const FEE_ACCOUNT: &str = "exchange";
+
+/// Which of a user's balances in a token an entry moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Account {
+    Available,
+    Locked,
+    /// Funds outside the exchange. Deposits are paid out of it, so it runs
+    /// negative by what the user has brought in.
+    External,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LedgerReason {
+    Deposit,
+    /// Funds reserved for an order.
+    Lock,
+    /// Reserved funds released by a cancel, expiry, amend or unspent sweep.
+    Unlock,
+    /// Both legs of a trade, including any reserve the buyer gets back.
+    Fill,
+    /// Funds paid out to the user's external account.
+    Withdrawal,
+    /// A charge moved to the external account of `FEE_ACCOUNT`.
+    Fee,
+}
+
+/// One side of a balance movement. Every entry belongs to a transfer whose
+/// entries sum to zero per token, so the sum of all accounts of a token is
+/// always zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub entry_id: u64,
+    pub transfer_id: u64,
+    /// Unix millis of the command that caused the transfer.
+    pub timestamp: i64,
+    pub user_id: String,
+    pub token: String,
+    pub account: Account,
+    pub reason: LedgerReason,
+    /// The order for locks and unlocks, the trade for fills, the withdrawal
+    /// for withdrawals and their fees.
+    pub reference_id: Option<Uuid>,
+    /// Signed change to the account.
+    pub amount: Decimal,
+    pub before: Decimal,
+    pub after: Decimal,
+}
+
+/// A movement of `amount` into one account, negative to move funds out.
+#[derive(Debug, Clone, Copy)]
+pub struct Posting<'a> {
+    pub user_id: &'a str,
+    pub token: &'a str,
+    pub account: Account,
+    pub amount: Decimal,
+}
+
+/// Why a transfer happened, stamped on each of its entries.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferCause {
+    pub reason: LedgerReason,
+    pub reference_id: Option<Uuid>,
+    pub timestamp: i64,
+}
+
+/// The audit trail behind `BalanceManager`, which records a transfer here
+/// for every change it makes. Also holds the external accounts, which have
+/// no balance of their own. Only the counters and external balances are
+/// state; entries are history and stay out of snapshots and the state hash.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Ledger {
+    /// Each user's newest `LEDGER_ENTRIES_KEPT` entries, oldest first.
+    #[serde(skip)]
+    entries: HashMap<String, VecDeque<LedgerEntry>>,
+    /// user -> token -> external account balance.
+    external: HashMap<String, HashMap<String, Decimal>>,
+    next_entry_id: u64,
+    next_transfer_id: u64,
+    /// Entries recorded since the last `take_new_entries`.
+    #[serde(skip)]
+    new_entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn external_balance(&self, user_id: &str, token: &str) -> Decimal {
+        self.external
+            .get(user_id)
+            .and_then(|balances| balances.get(token))
+            .copied()
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    pub fn external_balance_mut(&mut self, user_id: &str, token: &str) -> &mut Decimal {
+        self.external
+            .entry(user_id.to_string())
+            .or_default()
+            .entry(token.to_string())
+            .or_default()
+    }
+
+    pub fn begin_transfer(&mut self) -> u64 {
+        self.next_transfer_id += 1;
+        self.next_transfer_id
+    }
+
+    /// Records `posting` as part of `transfer_id`, given the account balance
+    /// before it was applied.
+    pub fn record(
+        &mut self,
+        transfer_id: u64,
+        cause: &TransferCause,
+        posting: &Posting,
+        before: Decimal,
+    ) {
+        self.next_entry_id += 1;
+        let entry = LedgerEntry {
+            entry_id: self.next_entry_id,
+            transfer_id,
+            timestamp: cause.timestamp,
+            user_id: posting.user_id.to_string(),
+            token: posting.token.to_string(),
+            account: posting.account,
+            reason: cause.reason,
+            reference_id: cause.reference_id,
+            amount: posting.amount,
+            before,
+            after: before + posting.amount,
+        };
+        self.new_entries.push(entry.clone());
+        let entries = self.entries.entry(posting.user_id.to_string()).or_default();
+        entries.push_back(entry);
+        if entries.len() > LEDGER_ENTRIES_KEPT {
+            entries.pop_front();
+        }
+    }
+
+    /// Drains the entries recorded since the last call, oldest first.
+    pub fn take_new_entries(&mut self) -> Vec<LedgerEntry> {
+        std::mem::take(&mut self.new_entries)
+    }
+
+    /// The user's entries still held in memory, oldest first.
+    pub fn user_entries(&self, user_id: &str) -> impl DoubleEndedIterator<Item = &LedgerEntry> {
+        self.entries.get(user_id).into_iter().flatten()
+    }
+}
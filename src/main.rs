@@ -1,32 +1,150 @@
-use crate::orderbook::Orderbook;
-use actix_web::web::{Data, Json};
+use actix::{Actor, Arbiter};
+use actix_web::web::Data;
 use actix_web::{App, HttpServer};
-use actix::Actor;
+use clock::{FixedClock, IdGenerator, RandomIds, SequentialIds, SystemClock};
 use engine::MatchingEngine;
-use routes::{cancel_order_route, create_order_route, get_depth_route, get_order_route};
+use routes::{
+    amend_order_route, cancel_all_orders_route, cancel_order_route, create_listen_key_route,
+    create_order_route, deposit_route, get_balances_route, get_depth_diffs_route,
+    get_market_candles_route, get_market_depth_route, get_market_ticker_route,
+    get_market_trades_route, get_order_route, get_state_hash_route, get_tickers_route,
+    get_user_ledger_route, get_user_orders_route, keep_alive_listen_key_route, market_stream_route,
+    revoke_listen_key_route, take_snapshot_route, user_stream_route, withdraw_route,
+};
+use stream::MarketDataHub;
 
+pub mod auth;
+pub mod balance;
+pub mod candles;
+pub mod clock;
 pub mod engine;
+pub mod history;
 pub mod input;
+pub mod journal;
+pub mod ledger;
+pub mod market;
 pub mod orderbook;
 pub mod output;
 pub mod routes;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stream;
+pub mod ticker;
+pub mod token;
+pub mod trade_spill;
+pub mod trades;
 
 #[actix_web::main]
 
 async fn main() -> Result<(), std::io::Error> {
-    let engine = MatchingEngine::new().start();
+    // Sequential IDs from a fixed seed make runs reproducible; random otherwise
+    let ids: Box<dyn IdGenerator> = match std::env::var("CEX_ID_SEED") {
+        Ok(seed) => Box::new(SequentialIds::new(
+            seed.parse().map_err(std::io::Error::other)?,
+        )),
+        Err(_) => Box::new(RandomIds),
+    };
+
+    // Audit mode: rebuild state from a journal, print its hash and exit.
+    // Segments older than the snapshots have been deleted, so start from one
+    if let Ok(path) = std::env::var("CEX_REPLAY") {
+        let mut engine = MatchingEngine::with_sources(Box::new(FixedClock(0)), ids);
+        if let Ok(dir) = std::env::var("CEX_SNAPSHOT_DIR")
+            && let Some(sequence) = engine.restore_snapshot(std::path::Path::new(&dir))?
+        {
+            println!("Restored snapshot at journal entry {}", sequence);
+        }
+        let replayed = engine.replay_journal(std::path::Path::new(&path))?;
+        let hash = engine.state_hash().map_err(std::io::Error::other)?;
+        println!(
+            "Replayed {} journal entries from {}, state hash {}",
+            replayed, path, hash
+        );
+        return Ok(());
+    }
+
+    let api_keys = auth::ApiKeys::from_env().map_err(std::io::Error::other)?;
+    if !api_keys.enabled() {
+        println!("CEX_API_KEYS is not set, listen keys cannot be issued");
+    }
+    if !api_keys.admin_enabled() {
+        println!("CEX_ADMIN_KEY is not set, the admin endpoints are disabled");
+    }
+
+    // Market data fan-out gets its own thread so it never competes with matching
+    let hub_arbiter = Arbiter::new();
+    let hub = MarketDataHub::start_in_arbiter(&hub_arbiter.handle(), |_| MarketDataHub::default());
+
+    let mut engine = MatchingEngine::with_sources(Box::new(SystemClock), ids);
+    #[cfg(feature = "sqlite")]
+    if let Ok(path) = std::env::var("CEX_SQLITE_PATH") {
+        // Fail at startup rather than in the writer thread. Attached before
+        // replay, which rewrites any history lost from the writer queue
+        sqlite::SqliteHistory::open(std::path::Path::new(&path)).map_err(std::io::Error::other)?;
+        let writer = actix::SyncArbiter::start(1, move || {
+            sqlite::SqliteHistory::open(std::path::Path::new(&path))
+                .expect("SQLite history database was just opened")
+        });
+        engine.set_history_sink(writer.recipient());
+    }
+    // Restore and replay before the hub is attached so rebuilt state is not
+    // streamed
+    if let Ok(dir) = std::env::var("CEX_SNAPSHOT_DIR") {
+        let dir = std::path::PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)?;
+        if let Some(sequence) = engine.restore_snapshot(&dir)? {
+            println!("Restored snapshot at journal entry {}", sequence);
+        }
+        engine.enable_snapshots(dir);
+    }
+    if let Ok(path) = std::env::var("CEX_JOURNAL_PATH") {
+        let replayed = engine.open_journal(std::path::Path::new(&path))?;
+        println!("Replayed {} journal entries from {}", replayed, path);
+    }
+    engine.set_market_data_hub(hub.clone());
+    if let Ok(dir) = std::env::var("CEX_TRADE_SPILL_DIR") {
+        // Like the SQLite writer, file I/O stays off the matching thread
+        trade_spill::TradeSpill::open(std::path::Path::new(&dir))?;
+        let spill = actix::SyncArbiter::start(1, move || {
+            trade_spill::TradeSpill::open(std::path::Path::new(&dir))
+                .expect("trade spill directory was just created")
+        });
+        engine.set_trade_spill(spill);
+    }
+    let engine = engine.start();
 
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(engine.clone()))
-            .app_data(Data::new(Orderbook::new()))
+            .app_data(Data::new(hub.clone()))
+            .app_data(Data::new(api_keys.clone()))
             // .service(create_order)
             // .service(delete_order)
             // .service(get_depth)
             .service(create_order_route)
             .service(get_order_route)
             .service(cancel_order_route)
-            .service(get_depth_route)
+            .service(amend_order_route)
+            .service(cancel_all_orders_route)
+            .service(get_market_depth_route)
+            .service(get_depth_diffs_route)
+            .service(get_market_trades_route)
+            .service(get_market_candles_route)
+            .service(get_market_ticker_route)
+            .service(get_tickers_route)
+            .service(deposit_route)
+            .service(withdraw_route)
+            .service(get_user_orders_route)
+            .service(get_user_ledger_route)
+            .service(get_balances_route)
+            .service(market_stream_route)
+            .service(create_listen_key_route)
+            .service(keep_alive_listen_key_route)
+            .service(revoke_listen_key_route)
+            .service(user_stream_route)
+            .service(take_snapshot_route)
+            .service(get_state_hash_route)
     })
     .bind("127.0.0.1:8080")?
     .run()
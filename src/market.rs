@@ -1,15 +1,24 @@
-use crate::orderbook::Orderbook;
+use crate::candles::CandleSeries;
+use crate::orderbook::{Orderbook, StopBook};
+use crate::ticker::{Ticker, TickerStats};
 use crate::token::TradingPair;
+use crate::trades::{TRADE_LOG_CAPACITY, TradeLog};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Market {
     pub pair: TradingPair,
     pub orderbook: Orderbook,
+    pub stop_book: StopBook,
+    pub trades: TradeLog,
+    pub candles: CandleSeries,
+    pub ticker_stats: TickerStats,
     pub base_liquidity: Decimal,  //available base token
     pub quote_liquidity: Decimal, //available quote token
     pub price: Decimal,           //current market price of the pair
+    pub tick_size: Decimal,       //smallest price increment
     pub is_active: bool,
 }
 
@@ -18,9 +27,14 @@ impl Market {
         Self {
             pair,
             orderbook: Orderbook::new(),
+            stop_book: StopBook::default(),
+            trades: TradeLog::new(TRADE_LOG_CAPACITY),
+            candles: CandleSeries::default(),
+            ticker_stats: TickerStats::default(),
             base_liquidity: Decimal::ZERO,
             quote_liquidity: Decimal::ZERO,
             price: initial_price,
+            tick_size: Decimal::new(1, 2), // 0.01
             is_active: true,
         }
     }
@@ -39,8 +53,35 @@ impl Market {
             self.pair.pair_symbol, base_amt, self.pair.base_tkn, quote_amt, self.pair.quote_tkn
         );
     }
+
+    /// 24h statistics as of `now`, with best bid/ask read from the book.
+    pub fn ticker(&self, now: i64) -> Ticker {
+        let stats = self.ticker_stats.window(now);
+
+        let price_change = stats.open.map(|open| self.price - open);
+        let price_change_percent = stats
+            .open
+            .zip(price_change)
+            .map(|(open, change)| (change / open * Decimal::ONE_HUNDRED).round_dp(2));
+
+        Ticker {
+            market: self.pair.pair_symbol.clone(),
+            last_price: self.price,
+            best_bid: self.orderbook.best_bid_level(),
+            best_ask: self.orderbook.best_ask_level(),
+            high: stats.high,
+            low: stats.low,
+            volume: stats.volume,
+            quote_volume: stats.quote_volume,
+            price_change,
+            price_change_percent,
+            vwap: stats.vwap,
+            trade_count: stats.trade_count,
+        }
+    }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MarketManager {
     markets: HashMap<String, Market>,
 }
@@ -52,10 +93,22 @@ impl MarketManager {
         }
     }
 
+    pub fn get_market(&self, key: &str) -> Option<&Market> {
+        self.markets.get(key)
+    }
+
     pub fn get_market_mut(&mut self, key: &str) -> Option<&mut Market> {
         self.markets.get_mut(key)
     }
 
+    pub fn markets(&self) -> impl Iterator<Item = &Market> {
+        self.markets.values()
+    }
+
+    pub fn markets_mut(&mut self) -> impl Iterator<Item = &mut Market> {
+        self.markets.values_mut()
+    }
+
     pub fn create_market(
         &mut self,
         pair: TradingPair,
@@ -1,25 +1,791 @@
-use crate::input::Order;
+use crate::clock::IdGenerator;
+use crate::input::{Fill, Order, SelfTradePrevention, Side};
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Depth diffs kept per book for clients catching up from a snapshot.
+pub const DEPTH_DIFF_HISTORY: usize = 1_000;
 
 // pub struct Orderbook {
 //     pub bids: HashMap<u32, Vec<Order>>,
 //     pub asks: HashMap<u32, Vec<Order>>,
 // }
 
+/// Result of matching one taker against the book.
+#[derive(Debug, Default)]
+pub struct MatchOutcome {
+    pub fills: Vec<Fill>,
+    /// Makers pulled from the book by self-trade prevention.
+    pub cancelled_makers: Vec<Order>,
+    /// Quantity removed from makers by decrement-and-cancel. A maker left
+    /// with nothing unfilled has also been pulled from the book.
+    pub decremented_makers: Vec<(Uuid, Decimal)>,
+    /// Self-trade prevention cancelled the taker's remainder.
+    pub cancel_taker: bool,
+}
+
+/// Levels changed by one book update. A quantity of zero means the level is
+/// gone. Sequences are contiguous, so a client holding a snapshot at `n`
+/// applies the diff with sequence `n + 1`, and so on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthDiff {
+    pub sequence: u64,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+/// Untriggered stop orders for one market, keyed by stop price.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopBook {
+    /// Fire when the last price rises to or above the stop price.
+    pub buy_stops: BTreeMap<Decimal, VecDeque<Order>>,
+    /// Fire when the last price falls to or below the stop price.
+    pub sell_stops: BTreeMap<Decimal, VecDeque<Order>>,
+}
+
+impl StopBook {
+    pub fn add_order(&mut self, order: Order) {
+        let Some(stop_price) = order.stop_price else {
+            return;
+        };
+        let book_side = match order.side {
+            Side::Buy => self.buy_stops.entry(stop_price).or_default(),
+            Side::Sell => self.sell_stops.entry(stop_price).or_default(),
+        };
+        book_side.push_back(order);
+    }
+
+    pub fn remove_order(
+        &mut self,
+        order_id: Uuid,
+        side: &Side,
+        stop_price: Decimal,
+    ) -> Option<Order> {
+        let book_side = match side {
+            Side::Buy => &mut self.buy_stops,
+            Side::Sell => &mut self.sell_stops,
+        };
+        let orders_at_price = book_side.get_mut(&stop_price)?;
+        let index = orders_at_price
+            .iter()
+            .position(|o| o.order_id == order_id)?;
+        let order = orders_at_price.remove(index);
+        if orders_at_price.is_empty() {
+            book_side.remove(&stop_price);
+        }
+        order
+    }
+
+    /// Removes and returns the next stop order released by `last_price`.
+    /// Buy stops fire lowest stop first, sell stops highest stop first, and
+    /// orders at the same stop price in arrival order.
+    pub fn pop_triggered(&mut self, last_price: Decimal) -> Option<Order> {
+        if let Some(mut entry) = self.buy_stops.first_entry()
+            && *entry.key() <= last_price
+        {
+            let order = entry.get_mut().pop_front();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            return order;
+        }
+
+        if let Some(mut entry) = self.sell_stops.last_entry()
+            && *entry.key() >= last_price
+        {
+            let order = entry.get_mut().pop_front();
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+            return order;
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Orderbook {
     pub bids: BTreeMap<std::cmp::Reverse<Decimal>, VecDeque<Order>>,
     pub asks: BTreeMap<Decimal, VecDeque<Order>>,
+    /// Sequence of the last committed update; 0 for an untouched book.
+    pub sequence: u64,
+    /// Levels touched since the last commit.
+    dirty_bids: BTreeSet<Decimal>,
+    dirty_asks: BTreeSet<Decimal>,
+    diffs: VecDeque<DepthDiff>,
 }
 
 impl Orderbook {
     pub fn new() -> Self {
-        Self {
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
+        Self::default()
+    }
+
+    fn mark_dirty(&mut self, side: &Side, price: Decimal) {
+        match side {
+            Side::Buy => self.dirty_bids.insert(price),
+            Side::Sell => self.dirty_asks.insert(price),
+        };
+    }
+
+    /// Turns the levels touched since the last commit into the next depth
+    /// diff. Returns `None` when nothing was touched.
+    pub fn commit_changes(&mut self) -> Option<DepthDiff> {
+        if self.dirty_bids.is_empty() && self.dirty_asks.is_empty() {
+            return None;
         }
+
+        let bids = std::mem::take(&mut self.dirty_bids)
+            .into_iter()
+            .rev()
+            .map(|price| {
+                let quantity = self
+                    .bids
+                    .get(&std::cmp::Reverse(price))
+                    .map_or(Decimal::ZERO, level_quantity);
+                (price, quantity)
+            })
+            .collect();
+        let asks = std::mem::take(&mut self.dirty_asks)
+            .into_iter()
+            .map(|price| {
+                let quantity = self.asks.get(&price).map_or(Decimal::ZERO, level_quantity);
+                (price, quantity)
+            })
+            .collect();
+
+        self.sequence += 1;
+        let diff = DepthDiff {
+            sequence: self.sequence,
+            bids,
+            asks,
+        };
+        self.diffs.push_back(diff.clone());
+        if self.diffs.len() > DEPTH_DIFF_HISTORY {
+            self.diffs.pop_front();
+        }
+        Some(diff)
+    }
+
+    /// Committed diffs with sequence `from` onwards, or an error when `from`
+    /// is older than the retained history and the client must resync from a
+    /// snapshot.
+    pub fn diffs_since(&self, from: u64) -> Result<Vec<DepthDiff>, String> {
+        let oldest = self
+            .diffs
+            .front()
+            .map_or(self.sequence + 1, |diff| diff.sequence);
+        if from < oldest && from <= self.sequence {
+            return Err(format!(
+                "Diffs before {} are no longer available, resync from a snapshot",
+                oldest
+            ));
+        }
+        Ok(self
+            .diffs
+            .iter()
+            .filter(|diff| diff.sequence >= from)
+            .cloned()
+            .collect())
+    }
+
+    pub fn add_order(&mut self, order: Order) {
+        self.mark_dirty(&order.side, order.price);
+        let book_side = match order.side {
+            Side::Buy => self.bids.entry(std::cmp::Reverse(order.price)).or_default(),
+            Side::Sell => self.asks.entry(order.price).or_default(),
+        };
+        book_side.push_back(order);
     }
+
+    pub fn remove_order(&mut self, order_id: Uuid, side: &Side, price: Decimal) {
+        self.mark_dirty(side, price);
+        let book_side = match side {
+            Side::Buy => self.bids.get_mut(&std::cmp::Reverse(price)),
+            Side::Sell => self.asks.get_mut(&price),
+        };
+
+        if let Some(orders_at_price) = book_side {
+            orders_at_price.retain(|o| o.order_id != order_id);
+            if orders_at_price.is_empty() {
+                match side {
+                    Side::Buy => self.bids.remove(&std::cmp::Reverse(price)),
+                    Side::Sell => self.asks.remove(&price),
+                };
+            }
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.bids.keys().next().map(|price| price.0)
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.asks.keys().next().copied()
+    }
+
+    /// Best bid price and the visible quantity resting there.
+    pub fn best_bid_level(&self) -> Option<(Decimal, Decimal)> {
+        let (price, orders) = self.bids.iter().next()?;
+        Some((price.0, level_quantity(orders)))
+    }
+
+    /// Best ask price and the visible quantity resting there.
+    pub fn best_ask_level(&self) -> Option<(Decimal, Decimal)> {
+        let (price, orders) = self.asks.iter().next()?;
+        Some((*price, level_quantity(orders)))
+    }
+
+    /// Mutable access to a resting order. Its level is treated as changed.
+    pub fn get_order_mut(
+        &mut self,
+        order_id: Uuid,
+        side: &Side,
+        price: Decimal,
+    ) -> Option<&mut Order> {
+        self.mark_dirty(side, price);
+        let orders_at_price = match side {
+            Side::Buy => self.bids.get_mut(&std::cmp::Reverse(price))?,
+            Side::Sell => self.asks.get_mut(&price)?,
+        };
+        orders_at_price.iter_mut().find(|o| o.order_id == order_id)
+    }
+
+    /// Whether a `side` order at `price` would trade immediately.
+    pub fn crosses(&self, side: &Side, price: Decimal) -> bool {
+        match side {
+            Side::Buy => self.best_ask().is_some_and(|ask| price >= ask),
+            Side::Sell => self.best_bid().is_some_and(|bid| price <= bid),
+        }
+    }
+
+    /// Walks the side a `side` order would take from, without mutating the
+    /// book, and returns the fills matching would make within `limit_price`.
+    /// `user_id`'s own orders are treated the way `self_trade_prevention`
+    /// would treat them during matching, including the shrinking of the
+    /// order under `DecrementAndCancel`.
+    pub fn sweep(
+        &self,
+        side: &Side,
+        mut quantity: Decimal,
+        limit_price: Option<Decimal>,
+        user_id: &str,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> Sweep {
+        let levels: Box<dyn Iterator<Item = (Decimal, &VecDeque<Order>)>> = match side {
+            Side::Buy => Box::new(self.asks.iter().map(|(price, orders)| (*price, orders))),
+            Side::Sell => Box::new(self.bids.iter().map(|(price, orders)| (price.0, orders))),
+        };
+
+        let mut sweep = Sweep::default();
+        'levels: for (price, orders_at_price) in levels {
+            let within_limit = match (side, limit_price) {
+                (_, None) => true,
+                (Side::Buy, Some(limit)) => price <= limit,
+                (Side::Sell, Some(limit)) => price >= limit,
+            };
+            if !within_limit {
+                break;
+            }
+
+            // Walk the level the way `match_level` would, rotating icebergs
+            // behind the rest of the queue each time a slice is used up
+            let mut queue: VecDeque<SweepSlot> = orders_at_price
+                .iter()
+                .map(|order| SweepSlot {
+                    order_id: order.order_id,
+                    own: order.user_id == user_id,
+                    display: order.display_quantity,
+                    shown: order.visible_quantity(),
+                    remaining: order.remaining_quantity(),
+                })
+                .collect();
+            while let Some(slot) = queue.front_mut() {
+                if sweep.filled >= quantity {
+                    break 'levels;
+                }
+                if slot.own {
+                    match self_trade_prevention {
+                        SelfTradePrevention::None => {}
+                        SelfTradePrevention::CancelNewest | SelfTradePrevention::CancelBoth => {
+                            break 'levels;
+                        }
+                        SelfTradePrevention::CancelOldest => {
+                            queue.pop_front();
+                            continue;
+                        }
+                        SelfTradePrevention::DecrementAndCancel => {
+                            let decrement = std::cmp::min(quantity - sweep.filled, slot.remaining);
+                            quantity -= decrement;
+                            slot.remaining -= decrement;
+                            if slot.remaining == Decimal::ZERO {
+                                queue.pop_front();
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                let take = std::cmp::min(slot.shown, quantity - sweep.filled);
+                sweep.filled += take;
+                sweep.cost += take * price;
+                sweep.fills.push((slot.order_id, price, take));
+                slot.remaining -= take;
+                slot.shown -= take;
+                if slot.remaining == Decimal::ZERO {
+                    queue.pop_front();
+                } else if slot.shown == Decimal::ZERO {
+                    let mut slot = queue.pop_front().unwrap();
+                    slot.shown = slot.display.unwrap_or(slot.remaining).min(slot.remaining);
+                    queue.push_back(slot);
+                }
+            }
+        }
+
+        sweep
+    }
+
+    /// Matches `taker_order` against the opposite side of the book with
+    /// price-time priority, never trading through `limit_price` (no bound when
+    /// `None`). Fully filled makers are removed from the book. Fills take
+    /// their trade IDs from `ids` and are stamped with `now`.
+    pub fn match_order(
+        &mut self,
+        taker_order: &mut Order,
+        limit_price: Option<Decimal>,
+        ids: &mut dyn IdGenerator,
+        now: i64,
+    ) -> MatchOutcome {
+        let mut outcome = MatchOutcome::default();
+
+        match taker_order.side {
+            Side::Sell => {
+                while taker_order.filled_quantity < taker_order.quantity && !outcome.cancel_taker {
+                    let Some(mut level) = self.bids.first_entry() else {
+                        break;
+                    };
+                    if limit_price.is_some_and(|limit| limit > level.key().0) {
+                        break;
+                    } // Taker wants to sell for more than buyers are offering
+
+                    self.dirty_bids.insert(level.key().0);
+                    match_level(level.get_mut(), taker_order, &mut outcome, ids, now);
+                    if level.get().is_empty() {
+                        level.remove();
+                    }
+                }
+            }
+            Side::Buy => {
+                while taker_order.filled_quantity < taker_order.quantity && !outcome.cancel_taker {
+                    let Some(mut level) = self.asks.first_entry() else {
+                        break;
+                    };
+                    if limit_price.is_some_and(|limit| limit < *level.key()) {
+                        break;
+                    } // Taker wants to buy for less than sellers are asking
+
+                    self.dirty_asks.insert(*level.key());
+                    match_level(level.get_mut(), taker_order, &mut outcome, ids, now);
+                    if level.get().is_empty() {
+                        level.remove();
+                    }
+                }
+            }
+        }
+
+        outcome
+    }
+
+    /// Aggregated visible quantity per price level from the best price
+    /// outwards, at most `limit` per side; iceberg reserves are not shown.
+    /// With a `step`, levels are merged into buckets of that width: bids round
+    /// down and asks round up, so a bucket never looks better than its orders.
+    pub fn depth(
+        &self,
+        limit: Option<usize>,
+        step: Option<Decimal>,
+    ) -> crate::output::DepthResponse {
+        let bids = self
+            .bids
+            .iter()
+            .map(|(price, orders)| (price.0, level_quantity(orders)));
+        let asks = self
+            .asks
+            .iter()
+            .map(|(price, orders)| (*price, level_quantity(orders)));
+
+        crate::output::DepthResponse {
+            last_updated_id: self.sequence,
+            bids: depth_levels(bids, limit, step.map(|s| (s, false))),
+            asks: depth_levels(asks, limit, step.map(|s| (s, true))),
+        }
+    }
+}
+
+/// Buckets (when `bucket` is `Some((step, round_up))`), truncates and adds
+/// the running total to levels given best price first.
+fn depth_levels(
+    levels: impl Iterator<Item = (Decimal, Decimal)>,
+    limit: Option<usize>,
+    bucket: Option<(Decimal, bool)>,
+) -> Vec<(String, String, String)> {
+    let mut merged: Vec<(Decimal, Decimal)> = Vec::new();
+    for (price, quantity) in levels {
+        let price = match bucket {
+            Some((step, true)) => ((price / step).ceil() * step).normalize(),
+            Some((step, false)) => ((price / step).floor() * step).normalize(),
+            None => price,
+        };
+        // Levels arrive sorted, so equal buckets are always adjacent
+        match merged.last_mut() {
+            Some((last_price, total)) if *last_price == price => *total += quantity,
+            _ => {
+                if limit.is_some_and(|limit| merged.len() == limit) {
+                    break;
+                }
+                merged.push((price, quantity));
+            }
+        }
+    }
+
+    let mut cumulative = Decimal::ZERO;
+    merged
+        .into_iter()
+        .map(|(price, quantity)| {
+            cumulative += quantity;
+            (
+                price.to_string(),
+                quantity.to_string(),
+                cumulative.to_string(),
+            )
+        })
+        .collect()
+}
+
+/// What matching an order would do, as worked out by `Orderbook::sweep`.
+#[derive(Debug, Default)]
+pub struct Sweep {
+    /// Quantity that would fill.
+    pub filled: Decimal,
+    /// Quote cost of `filled`.
+    pub cost: Decimal,
+    /// `(maker order, price, quantity)` of each fill, in matching order.
+    pub fills: Vec<(Uuid, Decimal, Decimal)>,
+}
+
+/// A resting order as `Orderbook::sweep` sees it.
+struct SweepSlot {
+    order_id: Uuid,
+    own: bool,
+    display: Option<Decimal>,
+    shown: Decimal,
+    remaining: Decimal,
+}
+
+/// Visible quantity resting at one price level.
+fn level_quantity(orders: &VecDeque<Order>) -> Decimal {
+    orders.iter().map(|o| o.visible_quantity()).sum()
 }
 
-// Removed an outdated `create_order` helper that did not match current types
+/// Fills `taker_order` against one price level in queue order. A maker only
+/// trades its visible slice; an iceberg whose slice is used up is refilled
+/// from its reserve and moved to the back of the level. Makers owned by the
+/// taker's user are handled by the taker's self-trade prevention mode.
+fn match_level(
+    orders_at_price: &mut VecDeque<Order>,
+    taker_order: &mut Order,
+    outcome: &mut MatchOutcome,
+    ids: &mut dyn IdGenerator,
+    now: i64,
+) {
+    while taker_order.filled_quantity < taker_order.quantity {
+        let Some(maker_order) = orders_at_price.front_mut() else {
+            break;
+        };
+
+        if maker_order.user_id == taker_order.user_id {
+            match taker_order.self_trade_prevention {
+                SelfTradePrevention::None => {}
+                SelfTradePrevention::CancelNewest => {
+                    outcome.cancel_taker = true;
+                    return;
+                }
+                SelfTradePrevention::CancelOldest => {
+                    let maker_order = orders_at_price.pop_front().unwrap();
+                    outcome.cancelled_makers.push(maker_order);
+                    continue;
+                }
+                SelfTradePrevention::CancelBoth => {
+                    let maker_order = orders_at_price.pop_front().unwrap();
+                    outcome.cancelled_makers.push(maker_order);
+                    outcome.cancel_taker = true;
+                    return;
+                }
+                SelfTradePrevention::DecrementAndCancel => {
+                    let decrement = std::cmp::min(
+                        taker_order.remaining_quantity(),
+                        maker_order.remaining_quantity(),
+                    );
+                    taker_order.quantity -= decrement;
+                    maker_order.quantity -= decrement;
+                    outcome
+                        .decremented_makers
+                        .push((maker_order.order_id, decrement));
+                    if maker_order.remaining_quantity() == Decimal::ZERO {
+                        orders_at_price.pop_front();
+                    }
+                    continue;
+                }
+            }
+        }
+
+        let shown = maker_order.visible_quantity();
+        let trade_qty = std::cmp::min(taker_order.quantity - taker_order.filled_quantity, shown);
+
+        taker_order.filled_quantity += trade_qty;
+        maker_order.filled_quantity += trade_qty;
+
+        outcome.fills.push(Fill {
+            trade_id: ids.next_id(),
+            price: maker_order.price,
+            quantity: trade_qty,
+            maker_order_id: maker_order.order_id,
+            taker_order_id: taker_order.order_id,
+            timestamp: now,
+        });
+
+        if maker_order.quantity == maker_order.filled_quantity {
+            orders_at_price.pop_front();
+        } else if maker_order.display_quantity.is_some() && trade_qty == shown {
+            // The slice is used up; the next one loses time priority
+            let maker_order = orders_at_price.pop_front().unwrap();
+            orders_at_price.push_back(maker_order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SequentialIds;
+    use crate::input::{OrderStatus, OrderType, TimeInForce};
+
+    fn limit_order(
+        user_id: &str,
+        side: Side,
+        price: i64,
+        quantity: i64,
+        display_quantity: Option<i64>,
+    ) -> Order {
+        Order {
+            order_id: Uuid::new_v4(),
+            user_id: user_id.to_string(),
+            market: "TAN_KAN".to_string(),
+            side,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::Gtc,
+            post_only: false,
+            stop_price: None,
+            self_trade_prevention: SelfTradePrevention::CancelNewest,
+            display_quantity: display_quantity.map(Decimal::from),
+            expires_at: None,
+            price: Decimal::from(price),
+            quantity: Decimal::from(quantity),
+            filled_quantity: Decimal::ZERO,
+            timestamp: 0,
+            status: OrderStatus::New,
+            cancel_reason: None,
+        }
+    }
+
+    #[test]
+    fn iceberg_loses_priority_after_each_slice() {
+        let mut book = Orderbook::new();
+        let iceberg = limit_order("alice", Side::Sell, 10, 30, Some(5));
+        let later = limit_order("bob", Side::Sell, 10, 5, None);
+        book.add_order(iceberg.clone());
+        book.add_order(later.clone());
+
+        let mut taker = limit_order("carol", Side::Buy, 10, 12, None);
+        let outcome = book.match_order(
+            &mut taker,
+            Some(Decimal::from(10)),
+            &mut SequentialIds::new(1),
+            0,
+        );
+
+        let fills: Vec<(Uuid, Decimal)> = outcome
+            .fills
+            .iter()
+            .map(|fill| (fill.maker_order_id, fill.quantity))
+            .collect();
+        assert_eq!(
+            fills,
+            vec![
+                (iceberg.order_id, Decimal::from(5)),
+                (later.order_id, Decimal::from(5)),
+                (iceberg.order_id, Decimal::from(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn sweep_follows_iceberg_rotation_behind_own_order() {
+        let mut book = Orderbook::new();
+        book.add_order(limit_order("alice", Side::Sell, 10, 30, Some(5)));
+        book.add_order(limit_order("carol", Side::Sell, 10, 5, None));
+
+        // Matching takes one slice, rotates the iceberg behind carol's own
+        // order and stops there under cancel-newest
+        let sweep = book.sweep(
+            &Side::Buy,
+            Decimal::from(12),
+            Some(Decimal::from(10)),
+            "carol",
+            SelfTradePrevention::CancelNewest,
+        );
+        let mut taker = limit_order("carol", Side::Buy, 10, 12, None);
+        let outcome = book.match_order(
+            &mut taker,
+            Some(Decimal::from(10)),
+            &mut SequentialIds::new(1),
+            0,
+        );
+
+        assert_eq!(sweep.filled, Decimal::from(5));
+        assert_eq!(sweep.cost, Decimal::from(50));
+        assert_eq!(sweep.filled, taker.filled_quantity);
+        assert!(outcome.cancel_taker);
+    }
+
+    #[test]
+    fn sweep_counts_the_decrement_of_own_orders() {
+        let mut book = Orderbook::new();
+        book.add_order(limit_order("alice", Side::Sell, 10, 4, None));
+        book.add_order(limit_order("bob", Side::Sell, 10, 10, None));
+
+        let fillable = book
+            .sweep(
+                &Side::Buy,
+                Decimal::from(10),
+                Some(Decimal::from(10)),
+                "alice",
+                SelfTradePrevention::DecrementAndCancel,
+            )
+            .filled;
+        assert_eq!(fillable, Decimal::from(6));
+
+        let mut taker = limit_order("alice", Side::Buy, 10, 10, None);
+        taker.self_trade_prevention = SelfTradePrevention::DecrementAndCancel;
+        book.match_order(
+            &mut taker,
+            Some(Decimal::from(10)),
+            &mut SequentialIds::new(1),
+            0,
+        );
+        assert_eq!(taker.filled_quantity, fillable);
+    }
+
+    #[test]
+    fn self_trade_prevention_modes() {
+        // (mode, quantity traded, makers cancelled, makers decremented,
+        // taker cancelled)
+        for (mode, traded, cancelled, decremented, cancel_taker) in [
+            (SelfTradePrevention::None, 10, 0, 0, false),
+            (SelfTradePrevention::CancelNewest, 0, 0, 0, true),
+            (SelfTradePrevention::CancelOldest, 6, 1, 0, false),
+            (SelfTradePrevention::CancelBoth, 0, 1, 0, true),
+            (SelfTradePrevention::DecrementAndCancel, 6, 0, 1, false),
+        ] {
+            let mut book = Orderbook::new();
+            book.add_order(limit_order("alice", Side::Sell, 10, 4, None));
+            book.add_order(limit_order("bob", Side::Sell, 10, 6, None));
+            let sweep = book.sweep(&Side::Buy, Decimal::from(10), None, "alice", mode);
+
+            let mut taker = limit_order("alice", Side::Buy, 10, 10, None);
+            taker.self_trade_prevention = mode;
+            let outcome = book.match_order(
+                &mut taker,
+                Some(Decimal::from(10)),
+                &mut SequentialIds::new(1),
+                0,
+            );
+
+            assert_eq!(taker.filled_quantity, Decimal::from(traded), "{:?}", mode);
+            assert_eq!(sweep.filled, taker.filled_quantity, "{:?}", mode);
+            assert_eq!(outcome.cancelled_makers.len(), cancelled, "{:?}", mode);
+            assert_eq!(outcome.decremented_makers.len(), decremented, "{:?}", mode);
+            assert_eq!(outcome.cancel_taker, cancel_taker, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn depth_diffs_carry_changed_levels_in_sequence() {
+        let mut book = Orderbook::new();
+        assert!(book.commit_changes().is_none());
+
+        book.add_order(limit_order("alice", Side::Buy, 9, 5, None));
+        book.add_order(limit_order("bob", Side::Sell, 10, 4, None));
+        let first = book.commit_changes().unwrap();
+        assert_eq!(first.sequence, 1);
+        assert_eq!(first.bids, [(Decimal::from(9), Decimal::from(5))]);
+        assert_eq!(first.asks, [(Decimal::from(10), Decimal::from(4))]);
+
+        // A taker clears the ask level, which is reported as quantity zero
+        let mut taker = limit_order("carol", Side::Buy, 10, 4, None);
+        book.match_order(
+            &mut taker,
+            Some(Decimal::from(10)),
+            &mut SequentialIds::new(1),
+            0,
+        );
+        let second = book.commit_changes().unwrap();
+        assert_eq!(second.sequence, 2);
+        assert!(second.bids.is_empty());
+        assert_eq!(second.asks, [(Decimal::from(10), Decimal::ZERO)]);
+        assert_eq!(book.depth(None, None).last_updated_id, 2);
+
+        let sequences =
+            |diffs: Vec<DepthDiff>| diffs.iter().map(|d| d.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences(book.diffs_since(1).unwrap()), [1, 2]);
+        assert_eq!(sequences(book.diffs_since(3).unwrap()), Vec::<u64>::new());
+
+        // Once the first diffs are dropped, a client that far behind resyncs
+        for _ in 0..DEPTH_DIFF_HISTORY {
+            book.add_order(limit_order("alice", Side::Buy, 8, 1, None));
+            book.commit_changes();
+        }
+        assert!(book.diffs_since(2).is_err());
+        assert_eq!(book.diffs_since(3).unwrap().len(), DEPTH_DIFF_HISTORY);
+    }
+
+    #[test]
+    fn depth_merges_levels_into_steps_and_limits_them() {
+        let mut book = Orderbook::new();
+        for (price, quantity) in [(95, 1), (97, 2), (99, 3), (89, 4)] {
+            book.add_order(limit_order("alice", Side::Buy, price, quantity, None));
+        }
+        for (price, quantity) in [(101, 1), (104, 2), (111, 3)] {
+            book.add_order(limit_order("bob", Side::Sell, price, quantity, None));
+        }
+        let level = |price: &str, quantity: &str, cumulative: &str| {
+            (
+                price.to_string(),
+                quantity.to_string(),
+                cumulative.to_string(),
+            )
+        };
+
+        // Bids round down and asks up, so no bucket looks better than it is
+        let depth = book.depth(Some(2), Some(Decimal::from(5)));
+        assert_eq!(depth.bids, [level("95", "6", "6"), level("85", "4", "10")]);
+        assert_eq!(depth.asks, [level("105", "3", "3"), level("115", "3", "6")]);
+
+        let depth = book.depth(Some(1), None);
+        assert_eq!(depth.bids, [level("99", "3", "3")]);
+        assert_eq!(depth.asks, [level("101", "1", "1")]);
+    }
+}
@@ -1,4 +1,13 @@
-use crate::input::Side;
+use crate::candles::{Candle, CandleInterval};
+use crate::input::{
+    CancelReason, Fill, Order, OrderStatus, OrderStatusFilter, OrderType, SelfTradePrevention,
+    Side, TimeInForce,
+};
+use crate::ledger::{Account, LedgerEntry, LedgerReason};
+use crate::orderbook::DepthDiff;
+use crate::ticker::Ticker;
+use crate::trades::Trade;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 #[derive(Deserialize, Serialize, Debug)]
 
@@ -19,24 +28,362 @@ pub struct DeleteOrderResponse {
 #[derive(Deserialize, Debug)]
 pub struct CreateOrderRequest {
     pub user_id: String,
+    pub market: String, // Trading pair symbol, eg "TAN_KAN"
     pub side: Side,
-    pub price: String, // Accept strings to avoid float precision issues from JSON
+    #[serde(default)]
+    pub order_type: OrderType,
+    pub price: Option<String>, // Accept strings to avoid float precision issues from JSON
     pub quantity: String,
+    pub max_slippage_bps: Option<u32>,
+    pub worst_price: Option<String>,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    pub expires_at: Option<i64>, // Unix millis, GTD only
+    #[serde(default)]
+    pub post_only: bool,
+    #[serde(default)]
+    pub post_only_reprice: bool,
+    pub stop_price: Option<String>, // StopMarket / StopLimit only
+    pub display_quantity: Option<String>, // Iceberg slice size
+    #[serde(default)]
+    pub self_trade_prevention: SelfTradePrevention,
 }
 
 #[derive(Serialize, Debug)]
 pub struct OrderResponse {
     pub order_id: String,
     pub user_id: String,
+    pub market: String,
     pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub expires_at: Option<i64>,
+    pub post_only: bool,
+    pub stop_price: Option<String>,
+    pub display_quantity: Option<String>,
+    pub self_trade_prevention: SelfTradePrevention,
     pub price: String,
     pub quantity: String,
     pub filled_quantity: String,
     pub timestamp: i64,
+    pub status: OrderStatus,
+    pub cancel_reason: Option<CancelReason>,
+}
+
+impl From<Order> for OrderResponse {
+    fn from(order: Order) -> Self {
+        Self {
+            order_id: order.order_id.to_string(),
+            user_id: order.user_id,
+            market: order.market,
+            side: order.side,
+            order_type: order.order_type,
+            time_in_force: order.time_in_force,
+            expires_at: order.expires_at,
+            post_only: order.post_only,
+            stop_price: order.stop_price.map(|p| p.to_string()),
+            display_quantity: order.display_quantity.map(|q| q.to_string()),
+            self_trade_prevention: order.self_trade_prevention,
+            price: order.price.to_string(),
+            quantity: order.quantity.to_string(),
+            filled_quantity: order.filled_quantity.to_string(),
+            timestamp: order.timestamp,
+            status: order.status,
+            cancel_reason: order.cancel_reason,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserOrdersQuery {
+    pub status: Option<OrderStatusFilter>,
+    pub market: Option<String>,
+    pub cursor: Option<usize>,
+    pub limit: Option<usize>,
 }
 
 #[derive(Serialize, Debug)]
-pub struct DepthResponse {
-    pub bids: Vec<(String, String)>,
+pub struct UserOrdersResponse {
+    pub orders: Vec<OrderResponse>,
+    pub next_cursor: Option<usize>, // Pass back as `cursor` for the next page
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TradesQuery {
+    pub limit: Option<usize>,
+    pub from_id: Option<u64>, // Trade sequence to start from
+}
+
+#[derive(Serialize, Debug)]
+pub struct TradeResponse {
+    pub sequence: u64,
+    pub trade_id: String,
+    pub price: String,
+    pub quantity: String,
+    pub aggressor_side: Side,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    pub timestamp: i64,
+}
+
+impl From<Trade> for TradeResponse {
+    fn from(trade: Trade) -> Self {
+        Self {
+            sequence: trade.sequence,
+            trade_id: trade.trade_id.to_string(),
+            price: trade.price.to_string(),
+            quantity: trade.quantity.to_string(),
+            aggressor_side: trade.aggressor_side,
+            maker_order_id: trade.maker_order_id.to_string(),
+            taker_order_id: trade.taker_order_id.to_string(),
+            timestamp: trade.timestamp,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CandlesQuery {
+    pub interval: CandleInterval, // "1m", "5m", "1h" or "1d"
+    pub start: Option<i64>,       // Unix millis
+    pub end: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CandleResponse {
+    pub open_time: i64,
+    pub open: String,
+    pub high: String,
+    pub low: String,
+    pub close: String,
+    pub base_volume: String,
+    pub quote_volume: String,
+    pub trade_count: u64,
+}
+
+impl From<Candle> for CandleResponse {
+    fn from(candle: Candle) -> Self {
+        Self {
+            open_time: candle.open_time,
+            open: candle.open.to_string(),
+            high: candle.high.to_string(),
+            low: candle.low.to_string(),
+            close: candle.close.to_string(),
+            base_volume: candle.base_volume.to_string(),
+            quote_volume: candle.quote_volume.to_string(),
+            trade_count: candle.trade_count,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct TickerResponse {
+    pub market: String,
+    pub last_price: String,
+    pub best_bid: Option<(String, String)>, // (price, size)
+    pub best_ask: Option<(String, String)>,
+    pub high_24h: Option<String>,
+    pub low_24h: Option<String>,
+    pub volume_24h: String, // Base token
+    pub quote_volume_24h: String,
+    pub price_change_24h: Option<String>,
+    pub price_change_percent_24h: Option<String>,
+    pub vwap_24h: Option<String>,
+    pub trade_count_24h: usize,
+}
+
+impl From<Ticker> for TickerResponse {
+    fn from(ticker: Ticker) -> Self {
+        Self {
+            market: ticker.market,
+            last_price: ticker.last_price.to_string(),
+            best_bid: ticker
+                .best_bid
+                .map(|(price, size)| (price.to_string(), size.to_string())),
+            best_ask: ticker
+                .best_ask
+                .map(|(price, size)| (price.to_string(), size.to_string())),
+            high_24h: ticker.high.map(|p| p.to_string()),
+            low_24h: ticker.low.map(|p| p.to_string()),
+            volume_24h: ticker.volume.to_string(),
+            quote_volume_24h: ticker.quote_volume.to_string(),
+            price_change_24h: ticker.price_change.map(|p| p.to_string()),
+            price_change_percent_24h: ticker.price_change_percent.map(|p| p.to_string()),
+            vwap_24h: ticker.vwap.map(|p| p.to_string()),
+            trade_count_24h: ticker.trade_count,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DepthDiffsQuery {
+    pub from_id: u64, // First diff sequence wanted, usually snapshot lastUpdatedId + 1
+}
+
+#[derive(Serialize, Debug)]
+pub struct DepthDiffResponse {
+    pub sequence: u64,
+    pub bids: Vec<(String, String)>, // (price, new total quantity); "0" removes the level
     pub asks: Vec<(String, String)>,
 }
+
+impl From<DepthDiff> for DepthDiffResponse {
+    fn from(diff: DepthDiff) -> Self {
+        let levels = |levels: Vec<(Decimal, Decimal)>| {
+            levels
+                .into_iter()
+                .map(|(price, quantity)| (price.to_string(), quantity.to_string()))
+                .collect()
+        };
+        Self {
+            sequence: diff.sequence,
+            bids: levels(diff.bids),
+            asks: levels(diff.asks),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DepthResponse {
+    #[serde(rename = "lastUpdatedId")]
+    pub last_updated_id: u64, // Book sequence this snapshot reflects
+    pub bids: Vec<(String, String, String)>, // (price, quantity, cumulative quantity)
+    pub asks: Vec<(String, String, String)>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DepthQuery {
+    pub limit: Option<usize>, // Levels per side; every level when omitted
+    pub step: Option<String>, // Price bucket width, e.g. "0.1"
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CancelAllOrdersQuery {
+    pub user_id: String,
+    pub market: Option<String>,
+    pub side: Option<Side>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CancelAllOrdersResponse {
+    pub status: String,
+    pub cancelled_order_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AmendOrderRequest {
+    pub user_id: String,
+    pub price: Option<String>,
+    pub quantity: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DepositRequest {
+    pub token: String,
+    pub amount: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WithdrawRequest {
+    pub token: String,
+    pub amount: String,
+}
+
+/// Whether a fill added liquidity to the book or took it.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// A fill as reported to the user who owns the order.
+#[derive(Serialize, Debug)]
+pub struct UserFillResponse {
+    pub trade_id: String,
+    pub price: String,
+    pub quantity: String,
+    pub liquidity: Liquidity,
+    pub timestamp: i64,
+}
+
+impl UserFillResponse {
+    pub fn new(fill: &Fill, liquidity: Liquidity) -> Self {
+        Self {
+            trade_id: fill.trade_id.to_string(),
+            price: fill.price.to_string(),
+            quantity: fill.quantity.to_string(),
+            liquidity,
+            timestamp: fill.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ListenKeyResponse {
+    pub listen_key: String,
+    /// Seconds until the key expires unless kept alive.
+    pub expires_in: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StateHashResponse {
+    pub journal_sequence: u64,
+    pub state_hash: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotResponse {
+    pub journal_sequence: u64,
+    pub taken_at: i64,
+    pub path: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BalanceResponse {
+    pub token: String,
+    pub available: String,
+    pub locked: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LedgerQuery {
+    pub token: Option<String>,
+    pub cursor: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LedgerEntryResponse {
+    pub entry_id: u64,
+    pub transfer_id: u64,
+    pub timestamp: i64,
+    pub token: String,
+    pub account: Account,
+    pub reason: LedgerReason,
+    pub reference_id: Option<String>,
+    pub amount: String,
+    pub before: String,
+    pub after: String,
+}
+
+impl From<LedgerEntry> for LedgerEntryResponse {
+    fn from(entry: LedgerEntry) -> Self {
+        Self {
+            entry_id: entry.entry_id,
+            transfer_id: entry.transfer_id,
+            timestamp: entry.timestamp,
+            token: entry.token,
+            account: entry.account,
+            reason: entry.reason,
+            reference_id: entry.reference_id.map(|id| id.to_string()),
+            amount: entry.amount.to_string(),
+            before: entry.before.to_string(),
+            after: entry.after.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct LedgerResponse {
+    pub entries: Vec<LedgerEntryResponse>,
+    pub next_cursor: Option<u64>, // Pass back as `cursor` for the next page
+}
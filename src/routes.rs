@@ -1,50 +1,41 @@
-use crate::engine::{CancelOrder, CreateOrder, GetDepth, GetOrder, MatchingEngine};
-use crate::input::Side;
-use crate::input::{CreateOrderInput, DeleteOrderInput, Depth};
-use crate::orderbook::Orderbook;
-use crate::output::{CreateOrderRequest, CreateOrderResponse, DeleteOrderResponse, OrderResponse};
+use crate::auth::{ApiKeys, AuthError};
+use crate::engine::{
+    AmendOrder, CancelAllOrders, CancelOrder, CreateMarketOrder, Deposit, EngineError, GetBalances,
+    GetCandles, GetDepthDiffs, GetLedger, GetMarketDepth, GetOrder, GetStateHash, GetTicker,
+    GetTickers, GetTrades, GetUserOrders, MatchingEngine, TakeSnapshot, Withdraw,
+};
+use crate::output::{
+    AmendOrderRequest, BalanceResponse, CancelAllOrdersQuery, CancelAllOrdersResponse,
+    CandleResponse, CandlesQuery, CreateOrderRequest, DepositRequest, DepthDiffResponse,
+    DepthDiffsQuery, DepthQuery, LedgerEntryResponse, LedgerQuery, LedgerResponse,
+    ListenKeyResponse, OrderResponse, SnapshotResponse, StateHashResponse, TickerResponse,
+    TradeResponse, TradesQuery, UserOrdersQuery, UserOrdersResponse, WithdrawRequest,
+};
+use crate::stream::{
+    CreateListenKey, KeepAliveListenKey, LISTEN_KEY_TTL, MarketDataHub, ResolveListenKey,
+    RevokeListenKey, WsSession,
+};
 use actix::Addr;
 use actix_web::web;
-use actix_web::HttpResponse;
-use actix_web::{
-    delete, get, post,
-    web::{Data, Json},
-    Responder,
-};
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::{Responder, delete, get, patch, post, put};
 use rust_decimal::Decimal;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
 #[post("/order")]
-pub async fn create_order(
-    body: Json<CreateOrderInput>,
-    orderbook: Data<&Arc<Mutex<Orderbook>>>,
-) -> impl Responder {
-    let price = body.0.price;
-    let quantity = body.0.quantity;
-    let user_id = body.0.user_id;
-    let side = body.0.side;
-
-    let mut orderbook = orderbook.lock().unwrap();
-    orderbook.create_order(price, quantity, user_id, side);
-
-    return HttpResponse::Ok().json(CreateOrderResponse {
-        order_id: String::from("Response"),
-    });
-
-    // println!("{:?}", body);
-    // "Order created"
-}
-#[post("/order")]
-
 pub async fn create_order_route(
     req: web::Json<CreateOrderRequest>,
     engine_addr: web::Data<Addr<MatchingEngine>>,
 ) -> impl Responder {
     let order_data = req.into_inner();
 
-    let price = match Decimal::from_str(&order_data.price) {
+    let price = match order_data
+        .price
+        .as_deref()
+        .map(Decimal::from_str)
+        .transpose()
+    {
         Ok(p) => p,
         Err(_) => return HttpResponse::BadRequest().body("Invalid price format"),
     };
@@ -52,12 +43,50 @@ pub async fn create_order_route(
         Ok(q) => q,
         Err(_) => return HttpResponse::BadRequest().body("Invalid quantity format"),
     };
+    let worst_price = match order_data
+        .worst_price
+        .as_deref()
+        .map(Decimal::from_str)
+        .transpose()
+    {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid worst_price format"),
+    };
+    let stop_price = match order_data
+        .stop_price
+        .as_deref()
+        .map(Decimal::from_str)
+        .transpose()
+    {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid stop_price format"),
+    };
+    let display_quantity = match order_data
+        .display_quantity
+        .as_deref()
+        .map(Decimal::from_str)
+        .transpose()
+    {
+        Ok(q) => q,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid display_quantity format"),
+    };
 
-    let msg = CreateOrder {
+    let msg = CreateMarketOrder {
         user_id: order_data.user_id,
+        market: order_data.market,
         side: order_data.side,
+        order_type: order_data.order_type,
         price,
         quantity,
+        max_slippage_bps: order_data.max_slippage_bps,
+        worst_price,
+        time_in_force: order_data.time_in_force,
+        expires_at: order_data.expires_at,
+        post_only: order_data.post_only,
+        post_only_reprice: order_data.post_only_reprice,
+        stop_price,
+        display_quantity,
+        self_trade_prevention: order_data.self_trade_prevention,
     };
 
     match engine_addr.send(msg).await {
@@ -65,7 +94,7 @@ pub async fn create_order_route(
             status: "Order received".to_string(),
             order_id: order_id.to_string(),
         }),
-        Ok(Err(e)) => HttpResponse::InternalServerError().body(e),
+        Ok(Err(e)) => engine_error_response(e),
         Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
     }
 }
@@ -79,16 +108,8 @@ pub async fn get_order_route(
     let msg = GetOrder { order_id };
 
     match engine_addr.send(msg).await {
-        Ok(Ok(order)) => HttpResponse::Ok().json(OrderResponse {
-            order_id: order.order_id.to_string(),
-            user_id: order.user_id,
-            side: order.side,
-            price: order.price.to_string(),
-            quantity: order.quantity.to_string(),
-            filled_quantity: order.filled_quantity.to_string(),
-            timestamp: order.timestamp,
-        }),
-        Ok(Err(e)) => HttpResponse::NotFound().body(e),
+        Ok(Ok(order)) => HttpResponse::Ok().json(OrderResponse::from(order)),
+        Ok(Err(e)) => engine_error_response(e),
         Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
     }
 }
@@ -105,16 +126,491 @@ pub async fn cancel_order_route(
             status: "Cancel request accepted".to_string(),
             order_id: id.to_string(),
         }),
-        Ok(Err(e)) => HttpResponse::BadRequest().body(e),
+        Ok(Err(e)) => engine_error_response(e),
         Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
     }
 }
 
-#[get("/depth")]
-pub async fn get_depth_route(engine_addr: web::Data<Addr<MatchingEngine>>) -> impl Responder {
-    match engine_addr.send(GetDepth).await {
+#[delete("/orders")]
+pub async fn cancel_all_orders_route(
+    query: web::Query<CancelAllOrdersQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let filter = query.into_inner();
+    let msg = CancelAllOrders {
+        user_id: filter.user_id,
+        market: filter.market,
+        side: filter.side,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(ids)) => HttpResponse::Ok().json(CancelAllOrdersResponse {
+            status: "Orders cancelled".to_string(),
+            cancelled_order_ids: ids.iter().map(|id| id.to_string()).collect(),
+        }),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[patch("/order/{order_id}")]
+pub async fn amend_order_route(
+    path: web::Path<Uuid>,
+    req: web::Json<AmendOrderRequest>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let order_id = path.into_inner();
+    let amend = req.into_inner();
+
+    let price = match amend.price.as_deref().map(Decimal::from_str).transpose() {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid price format"),
+    };
+    let quantity = match amend.quantity.as_deref().map(Decimal::from_str).transpose() {
+        Ok(q) => q,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid quantity format"),
+    };
+
+    let msg = AmendOrder {
+        order_id,
+        user_id: amend.user_id,
+        price,
+        quantity,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(id)) => HttpResponse::Ok().json(crate::output::CreateOrderResponse {
+            status: "Order amended".to_string(),
+            order_id: id.to_string(),
+        }),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/markets/{pair}/depth")]
+pub async fn get_market_depth_route(
+    path: web::Path<String>,
+    query: web::Query<DepthQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let market_pair = path.into_inner();
+    let query = query.into_inner();
+    let step = match query.step.as_deref().map(Decimal::from_str).transpose() {
+        Ok(step) => step,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid step"),
+    };
+    let msg = GetMarketDepth {
+        market_pair,
+        limit: query.limit,
+        step,
+    };
+    match engine_addr.send(msg).await {
         Ok(Ok(depth)) => HttpResponse::Ok().json(depth),
-        Ok(Err(e)) => HttpResponse::BadRequest().body(e),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/markets/{pair}/trades")]
+pub async fn get_market_trades_route(
+    path: web::Path<String>,
+    query: web::Query<TradesQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let market_pair = path.into_inner();
+    let filter = query.into_inner();
+    let msg = GetTrades {
+        market_pair,
+        limit: filter.limit.unwrap_or(100),
+        from_id: filter.from_id,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(trades)) => HttpResponse::Ok().json(
+            trades
+                .into_iter()
+                .map(TradeResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/markets/{pair}/candles")]
+pub async fn get_market_candles_route(
+    path: web::Path<String>,
+    query: web::Query<CandlesQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let market_pair = path.into_inner();
+    let filter = query.into_inner();
+    let msg = GetCandles {
+        market_pair,
+        interval: filter.interval,
+        start: filter.start,
+        end: filter.end,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(candles)) => HttpResponse::Ok().json(
+            candles
+                .into_iter()
+                .map(CandleResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/markets/{pair}/ticker")]
+pub async fn get_market_ticker_route(
+    path: web::Path<String>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let market_pair = path.into_inner();
+    match engine_addr.send(GetTicker { market_pair }).await {
+        Ok(Ok(ticker)) => HttpResponse::Ok().json(TickerResponse::from(ticker)),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/tickers")]
+pub async fn get_tickers_route(engine_addr: web::Data<Addr<MatchingEngine>>) -> impl Responder {
+    match engine_addr.send(GetTickers).await {
+        Ok(tickers) => HttpResponse::Ok().json(
+            tickers
+                .into_iter()
+                .map(TickerResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/markets/{pair}/depth/diffs")]
+pub async fn get_depth_diffs_route(
+    path: web::Path<String>,
+    query: web::Query<DepthDiffsQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let market_pair = path.into_inner();
+    let msg = GetDepthDiffs {
+        market_pair,
+        from_id: query.into_inner().from_id,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(diffs)) => HttpResponse::Ok().json(
+            diffs
+                .into_iter()
+                .map(DepthDiffResponse::from)
+                .collect::<Vec<_>>(),
+        ),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[post("/users/{user_id}/deposit")]
+pub async fn deposit_route(
+    path: web::Path<String>,
+    req: web::Json<DepositRequest>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let deposit = req.into_inner();
+
+    let amount = match Decimal::from_str(&deposit.amount) {
+        Ok(a) => a,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid amount format"),
+    };
+
+    let msg = Deposit {
+        user_id,
+        token: deposit.token,
+        amount,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(())) => HttpResponse::Ok().json(serde_json::json!({ "status": "Deposit accepted" })),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[post("/users/{user_id}/withdraw")]
+pub async fn withdraw_route(
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<WithdrawRequest>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    if let Err(e) = api_keys.verify(&req, &user_id) {
+        return auth_error_response(e);
+    }
+    let withdrawal = body.into_inner();
+
+    let amount = match Decimal::from_str(&withdrawal.amount) {
+        Ok(a) => a,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid amount format"),
+    };
+
+    let msg = Withdraw {
+        user_id,
+        token: withdrawal.token,
+        amount,
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(withdrawal_id)) => HttpResponse::Ok().json(serde_json::json!({
+            "status": "Withdrawal accepted",
+            "withdrawal_id": withdrawal_id.to_string(),
+        })),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/users/{user_id}/orders")]
+pub async fn get_user_orders_route(
+    path: web::Path<String>,
+    query: web::Query<UserOrdersQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let filter = query.into_inner();
+    let msg = GetUserOrders {
+        user_id,
+        status: filter.status,
+        market: filter.market,
+        cursor: filter.cursor,
+        limit: filter.limit.unwrap_or(50),
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(page)) => HttpResponse::Ok().json(UserOrdersResponse {
+            orders: page.orders.into_iter().map(OrderResponse::from).collect(),
+            next_cursor: page.next_cursor,
+        }),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/users/{user_id}/ledger")]
+pub async fn get_user_ledger_route(
+    path: web::Path<String>,
+    query: web::Query<LedgerQuery>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    let filter = query.into_inner();
+    let msg = GetLedger {
+        user_id,
+        token: filter.token,
+        cursor: filter.cursor,
+        limit: filter.limit.unwrap_or(100),
+    };
+    match engine_addr.send(msg).await {
+        Ok(Ok(page)) => HttpResponse::Ok().json(LedgerResponse {
+            entries: page
+                .entries
+                .into_iter()
+                .map(LedgerEntryResponse::from)
+                .collect(),
+            next_cursor: page.next_cursor,
+        }),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/users/{user_id}/balances")]
+pub async fn get_balances_route(
+    path: web::Path<String>,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    match engine_addr.send(GetBalances { user_id }).await {
+        Ok(Ok(user_bal)) => {
+            let mut balances: Vec<BalanceResponse> = user_bal
+                .balances
+                .iter()
+                .map(|(token, bal)| BalanceResponse {
+                    token: token.clone(),
+                    available: bal.available.to_string(),
+                    locked: bal.locked.to_string(),
+                })
+                .collect();
+            balances.sort_by(|a, b| a.token.cmp(&b.token));
+            HttpResponse::Ok().json(balances)
+        }
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/ws")]
+pub async fn market_stream_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    hub: web::Data<Addr<MarketDataHub>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    actix_web_actors::ws::start(WsSession::new(hub.get_ref().clone()), &req, stream)
+}
+
+fn engine_error_response(e: EngineError) -> HttpResponse {
+    let mut response = match e {
+        EngineError::Rejected(_) => HttpResponse::BadRequest(),
+        EngineError::NotFound(_) => HttpResponse::NotFound(),
+        EngineError::Gone(_) => HttpResponse::Gone(),
+        EngineError::Unavailable(_) => HttpResponse::ServiceUnavailable(),
+        EngineError::Internal(_) => HttpResponse::InternalServerError(),
+    };
+    response.body(e.to_string())
+}
+
+fn auth_error_response(e: AuthError) -> HttpResponse {
+    match e {
+        AuthError::NotConfigured => {
+            HttpResponse::ServiceUnavailable().body("Keys for this endpoint are not configured")
+        }
+        AuthError::InvalidKey => HttpResponse::Unauthorized().body("Invalid API key"),
+    }
+}
+
+#[post("/users/{user_id}/listen-key")]
+pub async fn create_listen_key_route(
+    req: HttpRequest,
+    path: web::Path<String>,
+    hub: web::Data<Addr<MarketDataHub>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    let user_id = path.into_inner();
+    if let Err(e) = api_keys.verify(&req, &user_id) {
+        return auth_error_response(e);
+    }
+    match hub.send(CreateListenKey { user_id }).await {
+        Ok(listen_key) => HttpResponse::Ok().json(ListenKeyResponse {
+            listen_key: listen_key.to_string(),
+            expires_in: LISTEN_KEY_TTL.as_secs(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[put("/users/{user_id}/listen-key/{listen_key}")]
+pub async fn keep_alive_listen_key_route(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    hub: web::Data<Addr<MarketDataHub>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    let (user_id, listen_key) = path.into_inner();
+    if let Err(e) = api_keys.verify(&req, &user_id) {
+        return auth_error_response(e);
+    }
+    let Ok(listen_key) = Uuid::parse_str(&listen_key) else {
+        return HttpResponse::BadRequest().body("Invalid listen key");
+    };
+    match hub
+        .send(KeepAliveListenKey {
+            user_id,
+            listen_key,
+        })
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json(ListenKeyResponse {
+            listen_key: listen_key.to_string(),
+            expires_in: LISTEN_KEY_TTL.as_secs(),
+        }),
+        Ok(false) => HttpResponse::NotFound().body("Listen key not found"),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[delete("/users/{user_id}/listen-key/{listen_key}")]
+pub async fn revoke_listen_key_route(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    hub: web::Data<Addr<MarketDataHub>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    let (user_id, listen_key) = path.into_inner();
+    if let Err(e) = api_keys.verify(&req, &user_id) {
+        return auth_error_response(e);
+    }
+    let Ok(listen_key) = Uuid::parse_str(&listen_key) else {
+        return HttpResponse::BadRequest().body("Invalid listen key");
+    };
+    match hub
+        .send(RevokeListenKey {
+            user_id,
+            listen_key,
+        })
+        .await
+    {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Listen key not found"),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/ws/user/{listen_key}")]
+pub async fn user_stream_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    path: web::Path<String>,
+    hub: web::Data<Addr<MarketDataHub>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let Ok(listen_key) = Uuid::parse_str(&path.into_inner()) else {
+        return Ok(HttpResponse::Unauthorized().body("Invalid listen key"));
+    };
+    match hub.send(ResolveListenKey { listen_key }).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(HttpResponse::Unauthorized().body("Invalid listen key")),
+        Err(_) => return Ok(HttpResponse::InternalServerError().body("Actor mailbox error")),
+    }
+    actix_web_actors::ws::start(
+        WsSession::for_listen_key(hub.get_ref().clone(), listen_key),
+        &req,
+        stream,
+    )
+}
+
+#[post("/admin/snapshot")]
+pub async fn take_snapshot_route(
+    req: HttpRequest,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    if let Err(e) = api_keys.verify_admin(&req) {
+        return auth_error_response(e);
+    }
+    match engine_addr.send(TakeSnapshot).await {
+        Ok(Ok(info)) => HttpResponse::Ok().json(SnapshotResponse {
+            journal_sequence: info.journal_sequence,
+            taken_at: info.taken_at,
+            path: info.path.display().to_string(),
+        }),
+        Ok(Err(e)) => engine_error_response(e),
+        Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
+    }
+}
+
+#[get("/admin/state-hash")]
+pub async fn get_state_hash_route(
+    req: HttpRequest,
+    engine_addr: web::Data<Addr<MatchingEngine>>,
+    api_keys: web::Data<ApiKeys>,
+) -> impl Responder {
+    if let Err(e) = api_keys.verify_admin(&req) {
+        return auth_error_response(e);
+    }
+    match engine_addr.send(GetStateHash).await {
+        Ok(Ok((journal_sequence, state_hash))) => HttpResponse::Ok().json(StateHashResponse {
+            journal_sequence,
+            state_hash,
+        }),
+        Ok(Err(e)) => engine_error_response(e),
         Err(_) => HttpResponse::InternalServerError().body("Actor mailbox error"),
     }
 }
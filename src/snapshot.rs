@@ -0,0 +1,148 @@
+use crate::balance::BalanceManager;
+use crate::input::Order;
+use crate::market::MarketManager;
+use crate::token::TokenRegistry;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Bumped whenever the layout of `EngineState` changes. Snapshots of any
+/// other version are skipped on restore, falling back to the journal.
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// How often the engine snapshots itself when snapshots are enabled.
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Snapshots kept on disk; older ones are deleted after each new one.
+pub const SNAPSHOTS_KEPT: usize = 3;
+
+/// Start of every snapshot file, followed by the version and a CRC32 of the
+/// payload, both little-endian `u32`s, then the MessagePack payload.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"CEXSNAP\0";
+
+/// Everything needed to rebuild the matching engine. Borrowed when written,
+/// owned when loaded.
+#[derive(Serialize, Deserialize)]
+pub struct EngineState<'a> {
+    pub token_registry: Cow<'a, TokenRegistry>,
+    pub market_manager: Cow<'a, MarketManager>,
+    pub balance_manager: Cow<'a, BalanceManager>,
+    pub orders: Cow<'a, HashMap<Uuid, Order>>,
+    pub user_orders: Cow<'a, HashMap<String, Vec<Uuid>>>,
+    pub gtd_expiries: Cow<'a, BTreeSet<(i64, Uuid)>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot<'a> {
+    /// Last journal entry reflected in `state`; replay resumes after it.
+    pub journal_sequence: u64,
+    /// Unix millis.
+    pub taken_at: i64,
+    /// `IdGenerator::position` when the snapshot was taken.
+    pub id_position: u64,
+    pub state: EngineState<'a>,
+}
+
+/// SHA-256 of `state`, hex encoded. Hashed as JSON, whose object keys are
+/// sorted, so the order of `HashMap` entries does not affect the result.
+pub fn state_hash(state: &EngineState) -> io::Result<String> {
+    let canonical = serde_json::to_value(state)?;
+    let digest = Sha256::digest(serde_json::to_vec(&canonical)?);
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Writes `snapshot` into `dir` as `snapshot-<journal sequence>-<taken_at>.bin`
+/// and prunes old ones. The file only appears under its final name once it
+/// is fully on disk.
+pub fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> io::Result<PathBuf> {
+    let payload = rmp_serde::to_vec(snapshot).map_err(io::Error::other)?;
+    let name = format!(
+        "snapshot-{:020}-{}.bin",
+        snapshot.journal_sequence, snapshot.taken_at
+    );
+    let path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()?;
+
+    for old in snapshot_files(dir)?.into_iter().skip(SNAPSHOTS_KEPT) {
+        fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+/// The newest snapshot in `dir` that reads back intact. Damaged snapshots and
+/// ones from another version are reported and skipped.
+pub fn load_latest_snapshot(dir: &Path) -> io::Result<Option<Snapshot<'static>>> {
+    for path in snapshot_files(dir)? {
+        match read_snapshot(&path) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(e) => println!("Skipping snapshot {}: {}", path.display(), e),
+        }
+    }
+    Ok(None)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Snapshot<'static>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let header_len = SNAPSHOT_MAGIC.len() + 8;
+    if bytes.len() < header_len || &bytes[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let version = word(SNAPSHOT_MAGIC.len());
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    let payload = &bytes[header_len..];
+    if crc32fast::hash(payload) != word(SNAPSHOT_MAGIC.len() + 4) {
+        return Err(invalid("checksum mismatch"));
+    }
+    rmp_serde::from_slice(payload).map_err(|e| invalid(&e.to_string()))
+}
+
+/// Journal sequence of the oldest snapshot in `dir`, read from its name.
+/// Journal entries up to it are no longer needed to restore.
+pub fn oldest_snapshot_sequence(dir: &Path) -> io::Result<Option<u64>> {
+    Ok(snapshot_files(dir)?.last().and_then(|path| {
+        path.file_name()?
+            .to_str()?
+            .strip_prefix("snapshot-")?
+            .split('-')
+            .next()?
+            .parse()
+            .ok()
+    }))
+}
+
+/// Snapshot files in `dir`, newest first.
+fn snapshot_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("snapshot-") && name.ends_with(".bin"))
+        })
+        .collect();
+    // Zero-padded sequences make name order match journal order
+    files.sort();
+    files.reverse();
+    Ok(files)
+}
@@ -0,0 +1,334 @@
+use crate::history::HistoryEvent;
+use crate::input::Order;
+use crate::ledger::LedgerEntry;
+use crate::trades::Trade;
+use actix::{Actor, Handler, SyncContext};
+use rusqlite::{Connection, params};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::path::Path;
+
+/// Amounts are stored as TEXT so they keep every decimal digit; cast them in
+/// SQL when approximate arithmetic is good enough. Every table is keyed by
+/// IDs the engine replays identically, so history written again after a
+/// restart replaces the same rows.
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+
+    CREATE TABLE IF NOT EXISTS orders (
+        order_id TEXT PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        market TEXT NOT NULL,
+        side TEXT NOT NULL,
+        order_type TEXT NOT NULL,
+        time_in_force TEXT NOT NULL,
+        price TEXT NOT NULL,
+        quantity TEXT NOT NULL,
+        filled_quantity TEXT NOT NULL,
+        status TEXT NOT NULL,
+        cancel_reason TEXT,
+        created_at INTEGER NOT NULL,
+        closed_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS orders_by_user ON orders (user_id, created_at);
+
+    CREATE TABLE IF NOT EXISTS trades (
+        market TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        trade_id TEXT NOT NULL,
+        price TEXT NOT NULL,
+        quantity TEXT NOT NULL,
+        aggressor_side TEXT NOT NULL,
+        maker_order_id TEXT NOT NULL,
+        taker_order_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        PRIMARY KEY (market, sequence)
+    );
+
+    CREATE TABLE IF NOT EXISTS balance_changes (
+        entry_id INTEGER PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        token TEXT NOT NULL,
+        available TEXT NOT NULL,
+        locked TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS balance_changes_by_user
+        ON balance_changes (user_id, token, entry_id);
+
+    CREATE TABLE IF NOT EXISTS ledger (
+        entry_id INTEGER PRIMARY KEY,
+        transfer_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        user_id TEXT NOT NULL,
+        token TEXT NOT NULL,
+        account TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        reference_id TEXT,
+        amount TEXT NOT NULL,
+        before TEXT NOT NULL,
+        after TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS ledger_by_user ON ledger (user_id, entry_id);
+    CREATE INDEX IF NOT EXISTS ledger_by_transfer ON ledger (transfer_id);
+";
+
+/// Writes engine history to a SQLite file. Runs on its own thread under a
+/// `SyncArbiter`, since every write blocks on disk.
+pub struct SqliteHistory {
+    conn: Connection,
+}
+
+impl SqliteHistory {
+    /// Opens or creates the database at `path` and its tables.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    fn write_order(&self, order: &Order, closed_at: i64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO orders (order_id, user_id, market, side, order_type,
+                 time_in_force, price, quantity, filled_quantity, status, cancel_reason,
+                 created_at, closed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                order.order_id.to_string(),
+                order.user_id,
+                order.market,
+                enum_text(&order.side),
+                enum_text(&order.order_type),
+                enum_text(&order.time_in_force),
+                order.price.to_string(),
+                order.quantity.to_string(),
+                order.filled_quantity.to_string(),
+                enum_text(&order.status),
+                order.cancel_reason.as_ref().map(enum_text),
+                order.timestamp,
+                closed_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn write_trades(&mut self, trades: &[Trade]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO trades (market, sequence, trade_id, price, quantity,
+                     aggressor_side, maker_order_id, taker_order_id, timestamp)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for trade in trades {
+                insert.execute(params![
+                    trade.market,
+                    trade.sequence,
+                    trade.trade_id.to_string(),
+                    trade.price.to_string(),
+                    trade.quantity.to_string(),
+                    enum_text(&trade.aggressor_side),
+                    trade.maker_order_id.to_string(),
+                    trade.taker_order_id.to_string(),
+                    trade.timestamp,
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    fn write_ledger_entries(&mut self, entries: &[LedgerEntry]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO ledger (entry_id, transfer_id, timestamp, user_id, token,
+                     account, reason, reference_id, amount, before, after)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for entry in entries {
+                insert.execute(params![
+                    entry.entry_id,
+                    entry.transfer_id,
+                    entry.timestamp,
+                    entry.user_id,
+                    entry.token,
+                    enum_text(&entry.account),
+                    enum_text(&entry.reason),
+                    entry.reference_id.map(|id| id.to_string()),
+                    entry.amount.to_string(),
+                    entry.before.to_string(),
+                    entry.after.to_string(),
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    fn write_balance_change(
+        &self,
+        entry_id: u64,
+        user_id: &str,
+        token: &str,
+        available: Decimal,
+        locked: Decimal,
+        timestamp: i64,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO balance_changes (entry_id, user_id, token, available,
+                 locked, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry_id,
+                user_id,
+                token,
+                available.to_string(),
+                locked.to_string(),
+                timestamp
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn write(&mut self, event: &HistoryEvent) -> rusqlite::Result<()> {
+        match event {
+            HistoryEvent::OrderClosed { order, closed_at } => self.write_order(order, *closed_at),
+            HistoryEvent::Trades(trades) => self.write_trades(trades),
+            HistoryEvent::LedgerEntries(entries) => self.write_ledger_entries(entries),
+            HistoryEvent::BalanceChanged {
+                entry_id,
+                user_id,
+                token,
+                available,
+                locked,
+                timestamp,
+            } => self
+                .write_balance_change(*entry_id, user_id, token, *available, *locked, *timestamp),
+        }
+    }
+}
+
+impl Actor for SqliteHistory {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<HistoryEvent> for SqliteHistory {
+    type Result = ();
+
+    fn handle(&mut self, msg: HistoryEvent, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(e) = self.write(&msg) {
+            println!("Failed to write history {:?}: {}", msg, e);
+        }
+    }
+}
+
+/// The name serde gives a unit enum variant, e.g. `Buy` or `GTC`.
+fn enum_text<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(text)) => text,
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Side;
+    use crate::ledger::{Account, LedgerReason};
+    use uuid::Uuid;
+
+    fn balance_change(entry_id: u64, available: i64) -> HistoryEvent {
+        HistoryEvent::BalanceChanged {
+            entry_id,
+            user_id: "alice".to_string(),
+            token: "KAN".to_string(),
+            available: Decimal::from(available),
+            locked: Decimal::ZERO,
+            timestamp: 1_000,
+        }
+    }
+
+    #[test]
+    fn history_written_twice_reads_back_once() {
+        let dir = std::env::temp_dir().join(format!("cex-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.db");
+        let events = [
+            HistoryEvent::Trades(vec![Trade {
+                sequence: 1,
+                trade_id: Uuid::new_v4(),
+                market: "TAN_KAN".to_string(),
+                price: Decimal::from(5),
+                quantity: Decimal::new(15, 1),
+                aggressor_side: Side::Buy,
+                maker_order_id: Uuid::new_v4(),
+                taker_order_id: Uuid::new_v4(),
+                timestamp: 1_000,
+            }]),
+            HistoryEvent::LedgerEntries(vec![LedgerEntry {
+                entry_id: 7,
+                transfer_id: 3,
+                timestamp: 1_000,
+                user_id: "alice".to_string(),
+                token: "KAN".to_string(),
+                account: Account::Available,
+                reason: LedgerReason::Deposit,
+                reference_id: None,
+                amount: Decimal::from(100),
+                before: Decimal::ZERO,
+                after: Decimal::from(100),
+            }]),
+            balance_change(7, 100),
+            balance_change(8, 90),
+        ];
+        let mut history = SqliteHistory::open(&path).unwrap();
+        for event in &events {
+            history.write(event).unwrap();
+        }
+        // A restart replays the same history into a reopened database
+        let mut history = SqliteHistory::open(&path).unwrap();
+        for event in &events {
+            history.write(event).unwrap();
+        }
+
+        let count = |table: &str| -> i64 {
+            history
+                .conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+        assert_eq!(count("trades"), 1);
+        assert_eq!(count("ledger"), 1);
+        assert_eq!(count("balance_changes"), 2);
+        let (quantity, side): (String, String) = history
+            .conn
+            .query_row(
+                "SELECT quantity, aggressor_side FROM trades WHERE market = 'TAN_KAN'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((quantity.as_str(), side.as_str()), ("1.5", "Buy"));
+        let (reason, after): (String, String) = history
+            .conn
+            .query_row(
+                "SELECT reason, after FROM ledger WHERE entry_id = 7",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((reason.as_str(), after.as_str()), ("Deposit", "100"));
+        let available: Vec<String> = history
+            .conn
+            .prepare("SELECT available FROM balance_changes ORDER BY entry_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(available, ["100", "90"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
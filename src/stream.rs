@@ -0,0 +1,539 @@
+use crate::candles::CandleInterval;
+use actix::{
+    Actor, ActorContext, Addr, AsyncContext, Context, Handler, Message, MessageResult, Recipient,
+    StreamHandler,
+};
+use actix_web_actors::ws;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Events a session may have queued before the hub treats it as too slow
+/// and drops it.
+pub const SESSION_MAILBOX_CAPACITY: usize = 256;
+
+/// How long a listen key stays valid without a keep-alive.
+pub const LISTEN_KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the hub drops expired listen keys and their sessions.
+const LISTEN_KEY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Market data for one channel, e.g. `trades@TAN_KAN`. Sent by the matching
+/// engine and fanned out by the hub.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish {
+    pub channel: String,
+    pub data: serde_json::Value,
+}
+
+/// Delivered by the hub to each subscribed session.
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub enum StreamEvent {
+    /// A serialized `{ "channel": .., "data": .. }` frame.
+    Data(Arc<str>),
+    /// The session fell behind and has been unsubscribed from everything.
+    Lagged,
+    /// The listen key the session was opened with expired or was revoked.
+    KeyExpired,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub session_id: Uuid,
+    pub channel: String,
+    pub recipient: Recipient<StreamEvent>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub session_id: Uuid,
+    pub channel: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub session_id: Uuid,
+}
+
+/// Issues a key that opens `user_id`'s private stream for `LISTEN_KEY_TTL`.
+#[derive(Message)]
+#[rtype(result = "Uuid")]
+pub struct CreateListenKey {
+    pub user_id: String,
+}
+
+/// The user a listen key belongs to, if it is valid.
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct ResolveListenKey {
+    pub listen_key: Uuid,
+}
+
+/// Restarts the `LISTEN_KEY_TTL` of `user_id`'s key. False if the key is
+/// unknown, expired or someone else's.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct KeepAliveListenKey {
+    pub user_id: String,
+    pub listen_key: Uuid,
+}
+
+/// Invalidates `user_id`'s key and closes the sessions opened with it. False
+/// if the key is unknown, expired or someone else's.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct RevokeListenKey {
+    pub user_id: String,
+    pub listen_key: Uuid,
+}
+
+/// Attaches a session to the private channel of a listen key's user, for as
+/// long as the key stays valid.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OpenUserStream {
+    pub listen_key: Uuid,
+    pub session_id: Uuid,
+    pub recipient: Recipient<StreamEvent>,
+}
+
+struct ListenKey {
+    user_id: String,
+    expires_at: Instant,
+    /// Sessions opened with this key, closed when it goes away.
+    sessions: HashMap<Uuid, Recipient<StreamEvent>>,
+}
+
+/// Private channel carrying one user's order and balance updates. Clients
+/// cannot subscribe to it directly; it is only attached to sessions opened
+/// with that user's listen key.
+pub fn user_channel(user_id: &str) -> String {
+    format!("user@{}", user_id)
+}
+
+/// Fans market data out to WebSocket sessions. Runs on its own arbiter so
+/// serialization and delivery never hold up matching.
+#[derive(Default)]
+pub struct MarketDataHub {
+    channels: HashMap<String, HashMap<Uuid, Recipient<StreamEvent>>>,
+    listen_keys: HashMap<Uuid, ListenKey>,
+}
+
+impl Actor for MarketDataHub {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(LISTEN_KEY_SWEEP_INTERVAL, |hub, _ctx| {
+            let now = Instant::now();
+            let expired: Vec<Uuid> = hub
+                .listen_keys
+                .iter()
+                .filter(|(_, key)| key.expires_at <= now)
+                .map(|(listen_key, _)| *listen_key)
+                .collect();
+            for listen_key in expired {
+                hub.close_listen_key(listen_key);
+            }
+        });
+    }
+}
+
+impl MarketDataHub {
+    fn remove_session(&mut self, session_id: Uuid) {
+        self.channels.retain(|_, sessions| {
+            sessions.remove(&session_id);
+            !sessions.is_empty()
+        });
+    }
+
+    /// The key if it exists, has not expired and belongs to `user_id`.
+    fn owned_listen_key(&mut self, listen_key: Uuid, user_id: &str) -> Option<&mut ListenKey> {
+        self.listen_keys
+            .get_mut(&listen_key)
+            .filter(|key| key.expires_at > Instant::now() && key.user_id == user_id)
+    }
+
+    /// Forgets `listen_key` and closes every session opened with it.
+    fn close_listen_key(&mut self, listen_key: Uuid) {
+        let Some(key) = self.listen_keys.remove(&listen_key) else {
+            return;
+        };
+        for (session_id, recipient) in key.sessions {
+            self.remove_session(session_id);
+            recipient.do_send(StreamEvent::KeyExpired);
+        }
+    }
+}
+
+impl Handler<Publish> for MarketDataHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _ctx: &mut Self::Context) -> Self::Result {
+        let targets = [Some(msg.channel.clone()), wildcard_channel(&msg.channel)];
+        let frame: Arc<str> = serde_json::json!({ "channel": msg.channel, "data": msg.data })
+            .to_string()
+            .into();
+
+        let mut lagging = Vec::new();
+        for channel in targets.iter().flatten() {
+            let Some(sessions) = self.channels.get(channel) else {
+                continue;
+            };
+            for (session_id, recipient) in sessions {
+                // Never wait on a session: a full mailbox means it is not
+                // keeping up, and a gap in its feed makes it unusable anyway
+                if recipient
+                    .try_send(StreamEvent::Data(frame.clone()))
+                    .is_err()
+                {
+                    lagging.push((*session_id, recipient.clone()));
+                }
+            }
+        }
+
+        for (session_id, recipient) in lagging {
+            println!("Dropping slow stream session {}", session_id);
+            self.remove_session(session_id);
+            recipient.do_send(StreamEvent::Lagged);
+        }
+    }
+}
+
+impl Handler<Subscribe> for MarketDataHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _ctx: &mut Self::Context) -> Self::Result {
+        self.channels
+            .entry(msg.channel)
+            .or_default()
+            .insert(msg.session_id, msg.recipient);
+    }
+}
+
+impl Handler<Unsubscribe> for MarketDataHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(sessions) = self.channels.get_mut(&msg.channel) {
+            sessions.remove(&msg.session_id);
+            if sessions.is_empty() {
+                self.channels.remove(&msg.channel);
+            }
+        }
+    }
+}
+
+impl Handler<CreateListenKey> for MarketDataHub {
+    type Result = MessageResult<CreateListenKey>;
+
+    fn handle(&mut self, msg: CreateListenKey, _ctx: &mut Self::Context) -> Self::Result {
+        let listen_key = Uuid::new_v4();
+        self.listen_keys.insert(
+            listen_key,
+            ListenKey {
+                user_id: msg.user_id,
+                expires_at: Instant::now() + LISTEN_KEY_TTL,
+                sessions: HashMap::new(),
+            },
+        );
+        MessageResult(listen_key)
+    }
+}
+
+impl Handler<ResolveListenKey> for MarketDataHub {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: ResolveListenKey, _ctx: &mut Self::Context) -> Self::Result {
+        self.listen_keys
+            .get(&msg.listen_key)
+            .filter(|key| key.expires_at > Instant::now())
+            .map(|key| key.user_id.clone())
+    }
+}
+
+impl Handler<KeepAliveListenKey> for MarketDataHub {
+    type Result = bool;
+
+    fn handle(&mut self, msg: KeepAliveListenKey, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(key) = self.owned_listen_key(msg.listen_key, &msg.user_id) else {
+            return false;
+        };
+        key.expires_at = Instant::now() + LISTEN_KEY_TTL;
+        true
+    }
+}
+
+impl Handler<RevokeListenKey> for MarketDataHub {
+    type Result = bool;
+
+    fn handle(&mut self, msg: RevokeListenKey, _ctx: &mut Self::Context) -> Self::Result {
+        if self
+            .owned_listen_key(msg.listen_key, &msg.user_id)
+            .is_none()
+        {
+            return false;
+        }
+        self.close_listen_key(msg.listen_key);
+        true
+    }
+}
+
+impl Handler<OpenUserStream> for MarketDataHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: OpenUserStream, _ctx: &mut Self::Context) -> Self::Result {
+        // The key may have gone away between the HTTP upgrade and now
+        let Some(key) = self
+            .listen_keys
+            .get_mut(&msg.listen_key)
+            .filter(|key| key.expires_at > Instant::now())
+        else {
+            msg.recipient.do_send(StreamEvent::KeyExpired);
+            return;
+        };
+        key.sessions.insert(msg.session_id, msg.recipient.clone());
+        self.channels
+            .entry(user_channel(&key.user_id))
+            .or_default()
+            .insert(msg.session_id, msg.recipient);
+    }
+}
+
+impl Handler<Disconnect> for MarketDataHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
+        self.remove_session(msg.session_id);
+        for key in self.listen_keys.values_mut() {
+            key.sessions.remove(&msg.session_id);
+        }
+    }
+}
+
+/// `kind@MARKET[:suffix]` becomes `kind@*[:suffix]`, so `ticker@*` receives
+/// every market's ticker.
+fn wildcard_channel(channel: &str) -> Option<String> {
+    let (kind, rest) = channel.split_once('@')?;
+    Some(match rest.split_once(':') {
+        Some((_, suffix)) => format!("{}@*:{}", kind, suffix),
+        None => format!("{}@*", kind),
+    })
+}
+
+/// Checks a channel name a client asked for: `depth@PAIR`, `trades@PAIR`,
+/// `ticker@PAIR` or `candles@PAIR:INTERVAL`, where `PAIR` may be `*`.
+pub fn validate_channel(channel: &str) -> Result<(), String> {
+    let (kind, rest) = channel
+        .split_once('@')
+        .ok_or_else(|| format!("Invalid channel {}", channel))?;
+    let market = match kind {
+        "depth" | "trades" | "ticker" => rest,
+        "candles" => {
+            let (market, interval) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("Channel {} needs an interval", channel))?;
+            CandleInterval::parse(interval)
+                .ok_or_else(|| format!("Unknown candle interval {}", interval))?;
+            market
+        }
+        _ => return Err(format!("Unknown channel kind {}", kind)),
+    };
+    if market.is_empty() {
+        return Err(format!("Channel {} has no market", channel));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "method", rename_all = "UPPERCASE")]
+enum ClientRequest {
+    Subscribe { channels: Vec<String> },
+    Unsubscribe { channels: Vec<String> },
+}
+
+/// One WebSocket connection. Clients send
+/// `{"method": "SUBSCRIBE", "channels": ["depth@TAN_KAN"]}` (or
+/// `UNSUBSCRIBE`) and receive `{"channel": .., "data": ..}` frames.
+pub struct WsSession {
+    id: Uuid,
+    hub: Addr<MarketDataHub>,
+    last_heartbeat: Instant,
+    /// Receives its user's private channel while this key stays valid.
+    listen_key: Option<Uuid>,
+}
+
+impl WsSession {
+    pub fn new(hub: Addr<MarketDataHub>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            hub,
+            last_heartbeat: Instant::now(),
+            listen_key: None,
+        }
+    }
+
+    /// A session that also receives the private updates of `listen_key`'s
+    /// user until the key expires or is revoked.
+    pub fn for_listen_key(hub: Addr<MarketDataHub>, listen_key: Uuid) -> Self {
+        Self {
+            listen_key: Some(listen_key),
+            ..Self::new(hub)
+        }
+    }
+
+    fn handle_request(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let request: ClientRequest = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => {
+                ctx.text(serde_json::json!({ "error": e.to_string() }).to_string());
+                return;
+            }
+        };
+
+        let (subscribe, channels) = match request {
+            ClientRequest::Subscribe { channels } => (true, channels),
+            ClientRequest::Unsubscribe { channels } => (false, channels),
+        };
+        if let Err(e) = channels.iter().try_for_each(|c| validate_channel(c)) {
+            ctx.text(serde_json::json!({ "error": e }).to_string());
+            return;
+        }
+
+        for channel in &channels {
+            if subscribe {
+                self.hub.do_send(Subscribe {
+                    session_id: self.id,
+                    channel: channel.clone(),
+                    recipient: ctx.address().recipient(),
+                });
+            } else {
+                self.hub.do_send(Unsubscribe {
+                    session_id: self.id,
+                    channel: channel.clone(),
+                });
+            }
+        }
+        let result = if subscribe {
+            "subscribed"
+        } else {
+            "unsubscribed"
+        };
+        ctx.text(serde_json::json!({ "result": result, "channels": channels }).to_string());
+    }
+}
+
+impl Actor for WsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(SESSION_MAILBOX_CAPACITY);
+        if let Some(listen_key) = self.listen_key {
+            self.hub.do_send(OpenUserStream {
+                listen_key,
+                session_id: self.id,
+                recipient: ctx.address().recipient(),
+            });
+        }
+        ctx.run_interval(HEARTBEAT_INTERVAL, |session, ctx| {
+            if Instant::now().duration_since(session.last_heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.hub.do_send(Disconnect {
+            session_id: self.id,
+        });
+    }
+}
+
+impl Handler<StreamEvent> for WsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: StreamEvent, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            StreamEvent::Data(frame) => ctx.text(&*frame),
+            StreamEvent::Lagged => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Slow consumer".to_string()),
+                }));
+                ctx.stop();
+            }
+            StreamEvent::KeyExpired => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Listen key expired or revoked".to_string()),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => self.handle_request(&text, ctx),
+            Ok(ws::Message::Ping(payload)) => {
+                self.last_heartbeat = Instant::now();
+                ctx.pong(&payload);
+            }
+            Ok(ws::Message::Pong(_)) => self.last_heartbeat = Instant::now(),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix::test]
+    async fn listen_keys_are_only_managed_by_their_owner() {
+        let hub = MarketDataHub::default().start();
+        let listen_key = hub
+            .send(CreateListenKey {
+                user_id: "alice".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let keep_alive = |user_id: &str| KeepAliveListenKey {
+            user_id: user_id.to_string(),
+            listen_key,
+        };
+        assert!(!hub.send(keep_alive("bob")).await.unwrap());
+        assert!(hub.send(keep_alive("alice")).await.unwrap());
+
+        let revoke = |user_id: &str| RevokeListenKey {
+            user_id: user_id.to_string(),
+            listen_key,
+        };
+        assert!(!hub.send(revoke("bob")).await.unwrap());
+        assert!(hub.send(revoke("alice")).await.unwrap());
+        assert_eq!(
+            hub.send(ResolveListenKey { listen_key }).await.unwrap(),
+            None
+        );
+        assert!(!hub.send(keep_alive("alice")).await.unwrap());
+    }
+}
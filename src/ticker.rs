@@ -0,0 +1,177 @@
+use crate::input::Fill;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Length of the rolling ticker window.
+pub const TICKER_WINDOW_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+/// Rolling 24h trade statistics for one market, updated as fills arrive and
+/// trimmed by journaled commands as trades age out of the window, so a
+/// replay trims at the same points.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TickerStats {
+    /// (index, timestamp, price, quantity) of every trade in the window,
+    /// oldest first.
+    trades: VecDeque<(u64, i64, Decimal, Decimal)>,
+    /// Candidates for the window high: prices strictly decreasing from the
+    /// front, so the front is the high.
+    highs: VecDeque<(u64, Decimal)>,
+    /// Candidates for the window low, prices strictly increasing.
+    lows: VecDeque<(u64, Decimal)>,
+    next_index: u64,
+    volume: Decimal,
+    quote_volume: Decimal,
+}
+
+impl TickerStats {
+    pub fn record(&mut self, fill: &Fill) {
+        let index = self.next_index;
+        self.next_index += 1;
+
+        self.trades
+            .push_back((index, fill.timestamp, fill.price, fill.quantity));
+        while self.highs.back().is_some_and(|(_, p)| *p <= fill.price) {
+            self.highs.pop_back();
+        }
+        self.highs.push_back((index, fill.price));
+        while self.lows.back().is_some_and(|(_, p)| *p >= fill.price) {
+            self.lows.pop_back();
+        }
+        self.lows.push_back((index, fill.price));
+
+        self.volume += fill.quantity;
+        self.quote_volume += fill.price * fill.quantity;
+    }
+
+    /// Drops trades older than the window ending at `now`. Only call from a
+    /// journaled command with that command's time: the trades kept are part
+    /// of the state hash.
+    pub fn evict(&mut self, now: i64) {
+        let cutoff = now - TICKER_WINDOW_MILLIS;
+        while let Some(&(index, timestamp, price, quantity)) = self.trades.front() {
+            if timestamp > cutoff {
+                break;
+            }
+            self.trades.pop_front();
+            self.volume -= quantity;
+            self.quote_volume -= price * quantity;
+            if self.highs.front().is_some_and(|(i, _)| *i == index) {
+                self.highs.pop_front();
+            }
+            if self.lows.front().is_some_and(|(i, _)| *i == index) {
+                self.lows.pop_front();
+            }
+        }
+    }
+
+    /// Statistics of the window ending at `now`. Reading never changes the
+    /// state: trades that aged out but have not been evicted yet, because no
+    /// command evicted since, are skipped instead.
+    pub fn window(&self, now: i64) -> WindowStats {
+        let cutoff = now - TICKER_WINDOW_MILLIS;
+        let stale = self
+            .trades
+            .partition_point(|(_, timestamp, _, _)| *timestamp <= cutoff);
+        let first_index = self.trades.get(stale).map_or(self.next_index, |t| t.0);
+
+        let mut volume = self.volume;
+        let mut quote_volume = self.quote_volume;
+        for (_, _, price, quantity) in self.trades.range(..stale) {
+            volume -= quantity;
+            quote_volume -= price * quantity;
+        }
+        WindowStats {
+            // Both queues are ordered by index, so the first candidate still
+            // in the window is the extreme of the window
+            high: self
+                .highs
+                .iter()
+                .find(|(i, _)| *i >= first_index)
+                .map(|(_, p)| *p),
+            low: self
+                .lows
+                .iter()
+                .find(|(i, _)| *i >= first_index)
+                .map(|(_, p)| *p),
+            open: self.trades.get(stale).map(|(_, _, p, _)| *p),
+            vwap: (volume > Decimal::ZERO).then(|| quote_volume / volume),
+            volume,
+            quote_volume,
+            trade_count: self.trades.len() - stale,
+        }
+    }
+}
+
+/// Trade statistics of one 24h window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowStats {
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    /// Price of the oldest trade in the window.
+    pub open: Option<Decimal>,
+    /// Base volume.
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+    /// Volume-weighted average price, `None` without trades.
+    pub vwap: Option<Decimal>,
+    pub trade_count: usize,
+}
+
+/// Snapshot of a market's ticker.
+#[derive(Debug, Clone)]
+pub struct Ticker {
+    pub market: String,
+    pub last_price: Decimal,
+    /// (price, visible size) of the best level on each side.
+    pub best_bid: Option<(Decimal, Decimal)>,
+    pub best_ask: Option<(Decimal, Decimal)>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+    /// Last price minus the first price traded in the window.
+    pub price_change: Option<Decimal>,
+    pub price_change_percent: Option<Decimal>,
+    pub vwap: Option<Decimal>,
+    pub trade_count: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn fill(price: i64, quantity: i64, timestamp: i64) -> Fill {
+        Fill {
+            trade_id: Uuid::new_v4(),
+            price: Decimal::from(price),
+            quantity: Decimal::from(quantity),
+            maker_order_id: Uuid::new_v4(),
+            taker_order_id: Uuid::new_v4(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn window_skips_trades_that_aged_out_without_evicting_them() {
+        let mut stats = TickerStats::default();
+        stats.record(&fill(9, 1, 0));
+        stats.record(&fill(4, 2, 1_000));
+        stats.record(&fill(6, 1, 2_000));
+
+        let now = TICKER_WINDOW_MILLIS + 500;
+        let window = stats.window(now);
+        assert_eq!(window.high, Some(Decimal::from(6)));
+        assert_eq!(window.low, Some(Decimal::from(4)));
+        assert_eq!(window.open, Some(Decimal::from(4)));
+        assert_eq!(window.volume, Decimal::from(3));
+        assert_eq!(window.quote_volume, Decimal::from(14));
+        assert_eq!(window.trade_count, 2);
+        assert_eq!(stats.trades.len(), 3);
+
+        stats.evict(now);
+        assert_eq!(stats.window(now), window);
+        assert_eq!(stats.trades.len(), 2);
+    }
+}
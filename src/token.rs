@@ -10,6 +10,8 @@ pub struct Token {
     pub decimals: u8,
     pub total_supply: Decimal,
     pub mint_authority: Option<String>,
+    /// Charged on every withdrawal, on top of the amount withdrawn.
+    pub withdrawal_fee: Decimal,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,9 +29,9 @@ impl TradingPair {
             pair_symbol: format!("{}_{}", base, quote),
         }
     }
+}
 
-
-
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TokenRegistry {
     tokens: HashMap<String, Token>,
 }
@@ -41,11 +43,13 @@ impl TokenRegistry {
         }
     }
 
-
-
-pub fn create_token(
-    &mut self, symbol: String, name: String,
-     decimals: u8, initial_supply: Decimal,
+    pub fn create_token(
+        &mut self,
+        symbol: String,
+        name: String,
+        decimals: u8,
+        initial_supply: Decimal,
+        withdrawal_fee: Decimal,
     ) -> Result<Token, String> {
         if self.tokens.contains_key(&symbol) {
             return Err(format!("Token {} already exists", symbol));
@@ -57,6 +61,7 @@ pub fn create_token(
             decimals,
             total_supply: initial_supply,
             mint_authority: None,
+            withdrawal_fee,
         };
 
         self.tokens.insert(symbol.clone(), token.clone());
@@ -67,7 +72,6 @@ pub fn create_token(
         self.tokens.get(symbol)
     }
 }
-}
 
 impl Default for TokenRegistry {
     fn default() -> Self {
@@ -82,12 +86,18 @@ impl Default for TokenRegistry {
             ("RAC", "Rac Token", 18, Decimal::new(1_000_000, 0)), // 1M RAC
         ];
 
-
         for (symbol, name, decimals, initial_supply) in tokens_to_create {
-            registry.create_token(symbol.to_string(), name.to_string(), decimals, initial_supply)
-            .expect("Failed to create token");
+            registry
+                .create_token(
+                    symbol.to_string(),
+                    name.to_string(),
+                    decimals,
+                    initial_supply,
+                    Decimal::new(1, 2), // 0.01 per withdrawal
+                )
+                .expect("Failed to create token");
         }
 
         registry
     }
-}
\ No newline at end of file
+}
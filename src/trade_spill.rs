@@ -0,0 +1,259 @@
+use crate::trades::Trade;
+use actix::{Actor, Handler, Message, SyncContext};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Every this many trades the spill index records where a trade starts in
+/// its file, so a read seeks close to `from` instead of scanning.
+const SPILL_INDEX_STRIDE: u64 = 1_000;
+
+/// Trades that fell out of a market's in-memory log.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SpillTrades {
+    pub market: String,
+    pub trades: Vec<Trade>,
+}
+
+/// Up to `limit` spilled trades of `market` starting at sequence `from`,
+/// oldest first.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<Trade>, String>")]
+pub struct ReadSpilledTrades {
+    pub market: String,
+    pub from: u64,
+    pub limit: usize,
+}
+
+/// Appends evicted trades to `<dir>/<pair>.jsonl` and serves reads of them.
+/// Runs on its own thread under a `SyncArbiter` like the SQLite writer, so
+/// neither the appends nor the reads hold up matching. Both go through one
+/// mailbox, so a read sees every trade spilled before it was sent.
+pub struct TradeSpill {
+    dir: PathBuf,
+    files: HashMap<String, SpillFile>,
+}
+
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    last_sequence: u64,
+    /// `(sequence, byte offset)` of every `SPILL_INDEX_STRIDE`th trade.
+    index: Vec<(u64, u64)>,
+}
+
+impl TradeSpill {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            files: HashMap::new(),
+        })
+    }
+
+    /// The spill file of `market`, indexed on first use.
+    fn spill_file(&mut self, market: &str) -> io::Result<&mut SpillFile> {
+        if !self.files.contains_key(market) {
+            let path = self.dir.join(format!("{}.jsonl", market));
+            self.files
+                .insert(market.to_string(), SpillFile::open(path)?);
+        }
+        Ok(self.files.get_mut(market).unwrap())
+    }
+}
+
+impl SpillFile {
+    /// Opens or creates the file at `path` and indexes what it holds. A torn
+    /// last line from a crash is cut off.
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut spill = Self {
+            path,
+            file,
+            len: 0,
+            last_sequence: 0,
+            index: Vec::new(),
+        };
+
+        let mut reader = BufReader::new(File::open(&spill.path)?);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let Some(trade) = line
+                .strip_suffix('\n')
+                .and_then(|json| serde_json::from_str::<Trade>(json).ok())
+            else {
+                break;
+            };
+            spill.note(trade.sequence, read as u64);
+        }
+        if spill.len < spill.file.metadata()?.len() {
+            println!(
+                "Trade spill {} is corrupt after byte {}, truncating",
+                spill.path.display(),
+                spill.len
+            );
+            spill.file.set_len(spill.len)?;
+        }
+        Ok(spill)
+    }
+
+    /// Accounts for a line of `len` bytes holding trade `sequence`, written
+    /// at the current end of the file.
+    fn note(&mut self, sequence: u64, len: u64) {
+        let indexed = self.index.last().map(|(sequence, _)| *sequence);
+        if indexed.is_none_or(|indexed| sequence >= indexed + SPILL_INDEX_STRIDE) {
+            self.index.push((sequence, self.len));
+        }
+        self.len += len;
+        self.last_sequence = sequence;
+    }
+
+    /// Appends the trades not already in the file, in one write.
+    fn append(&mut self, trades: &[Trade]) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut lines = Vec::new();
+        for trade in trades.iter().filter(|t| t.sequence > self.last_sequence) {
+            let start = buffer.len();
+            serde_json::to_writer(&mut buffer, trade)?;
+            buffer.push(b'\n');
+            lines.push((trade.sequence, (buffer.len() - start) as u64));
+        }
+        if let Err(e) = self.file.write_all(&buffer) {
+            // Keep the file ending where the index says it does
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+        for (sequence, len) in lines {
+            self.note(sequence, len);
+        }
+        Ok(())
+    }
+
+    fn read(&self, from: u64, limit: usize) -> io::Result<Vec<Trade>> {
+        // Last indexed trade at or before `from`
+        let offset = match self
+            .index
+            .partition_point(|(sequence, _)| *sequence <= from)
+        {
+            0 => 0,
+            at => self.index[at - 1].1,
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut trades = Vec::new();
+        // Only what is indexed: a torn line past `len` is not a trade yet
+        for line in BufReader::new(file.take(self.len - offset)).lines() {
+            let trade: Trade = serde_json::from_str(&line?)?;
+            if trade.sequence >= from {
+                trades.push(trade);
+                if trades.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok(trades)
+    }
+}
+
+impl Actor for TradeSpill {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<SpillTrades> for TradeSpill {
+    type Result = ();
+
+    fn handle(&mut self, msg: SpillTrades, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(e) = self
+            .spill_file(&msg.market)
+            .and_then(|spill| spill.append(&msg.trades))
+        {
+            println!(
+                "Failed to spill {} trades of {}: {}",
+                msg.trades.len(),
+                msg.market,
+                e
+            );
+        }
+    }
+}
+
+impl Handler<ReadSpilledTrades> for TradeSpill {
+    type Result = Result<Vec<Trade>, String>;
+
+    fn handle(&mut self, msg: ReadSpilledTrades, _ctx: &mut Self::Context) -> Self::Result {
+        self.spill_file(&msg.market)
+            .and_then(|spill| spill.read(msg.from, msg.limit))
+            .map_err(|e| format!("Failed to read spilled trades: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Side;
+    use rust_decimal::Decimal;
+    use uuid::Uuid;
+
+    fn trade(sequence: u64) -> Trade {
+        Trade {
+            sequence,
+            trade_id: Uuid::new_v4(),
+            market: "TAN_KAN".to_string(),
+            price: Decimal::from(5),
+            quantity: Decimal::ONE,
+            aggressor_side: Side::Buy,
+            maker_order_id: Uuid::new_v4(),
+            taker_order_id: Uuid::new_v4(),
+            timestamp: 0,
+        }
+    }
+
+    fn sequences(trades: &[Trade]) -> Vec<u64> {
+        trades.iter().map(|t| t.sequence).collect()
+    }
+
+    #[test]
+    fn spilled_trades_read_back_from_the_index_after_a_reopen() {
+        let dir = std::env::temp_dir().join(format!("cex-test-{}", Uuid::new_v4()));
+        let mut spill = TradeSpill::open(&dir).unwrap();
+        let trades: Vec<Trade> = (1..=2_500).map(trade).collect();
+        let file = spill.spill_file("TAN_KAN").unwrap();
+        file.append(&trades[..2_000]).unwrap();
+        // Already spilled trades are skipped
+        file.append(&trades[1_500..]).unwrap();
+        assert_eq!(file.index.len(), 3);
+        assert_eq!(
+            sequences(&file.read(1_999, 3).unwrap()),
+            [1_999, 2_000, 2_001]
+        );
+
+        // A torn line from a crash is cut off on reopen
+        let path = dir.join("TAN_KAN.jsonl");
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"sequence\":25")
+            .unwrap();
+        let mut reopened = TradeSpill::open(&dir).unwrap();
+        let file = reopened.spill_file("TAN_KAN").unwrap();
+        assert_eq!(file.last_sequence, 2_500);
+        assert_eq!(sequences(&file.read(2_499, 10).unwrap()), [2_499, 2_500]);
+        assert_eq!(sequences(&file.read(1, 2).unwrap()), [1, 2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::input::{Fill, Side};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// Trades kept in memory per market before older ones are spilled or dropped.
+pub const TRADE_LOG_CAPACITY: usize = 10_000;
+
+/// A public trade print.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    /// Per-market, starts at 1 and increases by one for every trade.
+    pub sequence: u64,
+    pub trade_id: Uuid,
+    pub market: String,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// Side of the taker order.
+    pub aggressor_side: Side,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub timestamp: i64,
+}
+
+/// Trade history for one market. The newest `capacity` trades are held in
+/// memory; older ones wait in `evicted` until the engine hands them to the
+/// trade spill, or drops them when there is none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TradeLog {
+    recent: VecDeque<Trade>,
+    capacity: usize,
+    next_sequence: u64,
+    /// Trades pushed out of `recent` since the last `take_evicted`.
+    #[serde(skip)]
+    evicted: Vec<Trade>,
+}
+
+impl TradeLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            recent: VecDeque::new(),
+            capacity,
+            next_sequence: 1,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Sequence of the latest trade, or 0 before the first one.
+    pub fn last_sequence(&self) -> u64 {
+        self.next_sequence - 1
+    }
+
+    /// Assigns the next sequence to `fill` and stores it.
+    pub fn record(&mut self, market: &str, fill: &Fill, aggressor_side: Side) -> Trade {
+        let trade = Trade {
+            sequence: self.next_sequence,
+            trade_id: fill.trade_id,
+            market: market.to_string(),
+            price: fill.price,
+            quantity: fill.quantity,
+            aggressor_side,
+            maker_order_id: fill.maker_order_id,
+            taker_order_id: fill.taker_order_id,
+            timestamp: fill.timestamp,
+        };
+        self.next_sequence += 1;
+        self.recent.push_back(trade.clone());
+
+        while self.recent.len() > self.capacity {
+            let evicted = self.recent.pop_front().unwrap();
+            self.evicted.push(evicted);
+        }
+        trade
+    }
+
+    /// Drains the trades evicted since the last call, oldest first.
+    pub fn take_evicted(&mut self) -> Vec<Trade> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Sequence of the oldest trade held in memory, or of the next trade
+    /// while none is.
+    pub fn oldest_sequence(&self) -> u64 {
+        self.recent
+            .front()
+            .map_or(self.next_sequence, |t| t.sequence)
+    }
+
+    /// The latest `limit` trades, oldest first.
+    pub fn latest(&self, limit: usize) -> Vec<Trade> {
+        let skip = self.recent.len().saturating_sub(limit);
+        self.recent.iter().skip(skip).cloned().collect()
+    }
+
+    /// Up to `limit` trades held in memory starting at sequence `from`,
+    /// oldest first.
+    pub fn from_sequence(&self, from: u64, limit: usize) -> Vec<Trade> {
+        self.recent
+            .iter()
+            .filter(|t| t.sequence >= from)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}